@@ -0,0 +1,177 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::str::FromStr;
+use std::sync::{Mutex, Arc};
+
+use mysql::PooledConn;
+use rocket::http::Status;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::model::*;
+use crate::analytics;
+use crate::events::*;
+use crate::units;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Moisture,
+    Temperature,
+    Humidity,
+    TankFill,
+    TankEmptyIn
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    Below,
+    Above
+}
+
+impl Metric {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Metric::Moisture => "moisture",
+            Metric::Temperature => "temperature",
+            Metric::Humidity => "humidity",
+            Metric::TankFill => "tank_fill",
+            Metric::TankEmptyIn => "tank_empty_in"
+        }
+    }
+
+    // `TankEmptyIn` is the predicted number of hours until the tank is empty,
+    // which isn't part of the reading itself.
+    pub fn value(&self, reading: &DataRow) -> Option<f32> {
+        match self {
+            Metric::Moisture => reading.moisture,
+            Metric::Temperature => reading.temperature,
+            Metric::Humidity => reading.humidity,
+            Metric::TankFill => reading.tank_fill,
+            Metric::TankEmptyIn => None
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "moisture" => Ok(Metric::Moisture),
+            "temperature" => Ok(Metric::Temperature),
+            "humidity" => Ok(Metric::Humidity),
+            "tank_fill" => Ok(Metric::TankFill),
+            "tank_empty_in" => Ok(Metric::TankEmptyIn),
+            _ => Err(())
+        }
+    }
+}
+
+impl Comparison {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Comparison::Below => "below",
+            Comparison::Above => "above"
+        }
+    }
+
+    pub fn holds(&self, value: f32, threshold: f32) -> bool {
+        match self {
+            Comparison::Below => value < threshold,
+            Comparison::Above => value > threshold
+        }
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "below" => Ok(Comparison::Below),
+            "above" => Ok(Comparison::Above),
+            _ => Err(())
+        }
+    }
+}
+
+// A rule fires once its condition has held for `duration` seconds and at most
+// once per `cooldown` seconds. It resolves on the first reading that doesn't
+// meet the condition. Readings without the rule's metric are ignored.
+pub fn evaluate(reading: &DataRow, db: &mut PooledConn, events: &Arc<Mutex<EventLog>>) -> Result<(), Status> {
+    let rules = get_alert_rules(reading.station, db)?;
+
+    // Only predicted when a rule needs it and the reading updated the tank.
+    let empty_in = if reading.tank_fill.is_some() && rules.iter().any(|r| r.metric == Metric::TankEmptyIn) {
+        analytics::predict_empty_at(reading, db)?.map(|t| t.saturating_sub(reading.time) as f32 / 3600.0)
+    } else {
+        None
+    };
+
+    for mut rule in rules.into_iter() {
+        let value = match rule.metric {
+            Metric::TankEmptyIn => empty_in,
+            metric => metric.value(reading)
+        };
+        let value = match value {
+            Some(value) => value,
+            None => continue
+        };
+        let time = reading.time;
+
+        if rule.comparison.holds(value, rule.threshold) {
+            let since = *rule.since.get_or_insert(time);
+            let cooled_down = rule.last_fired.map(|t| time >= t + rule.cooldown as usize).unwrap_or(true);
+
+            if !rule.firing && time >= since + rule.duration as usize && cooled_down {
+                rule.firing = true;
+                rule.last_fired = Some(time);
+                add_alert(rule.id, rule.station, value, time, db)?;
+                push(&rule, "firing", value, events)?;
+            }
+        } else {
+            rule.since = None;
+            if rule.firing {
+                rule.firing = false;
+                resolve_alerts(rule.id, time, db)?;
+                push(&rule, "resolved", value, events)?;
+            }
+        }
+
+        update_alert_rule(&rule, db)?;
+    }
+
+    Ok(())
+}
+
+fn push(rule: &AlertRuleRow, status: &str, value: f32, events: &Arc<Mutex<EventLog>>) -> Result<(), Status> {
+    let data = json!({
+        "rule": rule.id,
+        "status": status,
+        "metric": rule.metric,
+        "comparison": rule.comparison,
+        "threshold": rule.threshold,
+        "value": value,
+        "units": units::event_units()
+    });
+    events.lock().or(Err(Status::InternalServerError))?.push(rule.station, EventKind::Alert, data.to_string());
+    Ok(())
+}
@@ -0,0 +1,235 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use chrono::{NaiveDate, TimeZone};
+use chrono_tz::Tz;
+use mysql::PooledConn;
+use rocket::http::Status;
+
+use crate::model::*;
+use crate::alerts::Metric;
+use crate::anomalies;
+
+// Readings older than this don't influence the consumption rate.
+const PREDICTION_WINDOW: usize = 7 * 86400;
+// A rise in tank_fill between two readings of at least this much is a refill.
+const REFILL_RISE: f32 = 10.0;
+// Stored predictions are reused for this long unless the tank is refilled.
+const PREDICTION_TTL: usize = 15 * 60;
+
+#[derive(Debug)]
+pub struct Refill {
+    pub time: usize,
+    pub from: f32,
+    pub to: f32
+}
+
+#[derive(Debug)]
+pub struct Stats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32
+}
+
+#[derive(Debug)]
+pub struct Day {
+    pub date: NaiveDate,
+    pub count: usize,
+    pub moisture: Option<Stats>,
+    pub temperature: Option<Stats>,
+    pub humidity: Option<Stats>,
+    pub tank_fill: Option<Stats>
+}
+
+#[derive(Debug)]
+pub struct TankPrediction {
+    pub fill: Option<f32>,
+    pub rate: Option<f32>,
+    pub empty_at: Option<usize>,
+    pub last_refill: Option<usize>
+}
+
+// `readings` must be sorted by time.
+pub fn refills(readings: &[DataRow]) -> Vec<Refill> {
+    let fills: Vec<(usize, f32)> = readings.iter().filter_map(|d| d.tank_fill.map(|f| (d.time, f))).collect();
+    fills.windows(2)
+        .filter(|w| w[1].1 - w[0].1 >= REFILL_RISE)
+        .map(|w| Refill { time: w[1].0, from: w[0].1, to: w[1].1 })
+        .collect()
+}
+
+// Fits a line through the readings since the last refill. `rate` is the
+// consumption per day, the tank is only predicted to run empty if it is
+// actually being drained.
+pub fn predict(readings: &[DataRow]) -> TankPrediction {
+    let last_refill = refills(readings).last().map(|r| r.time);
+    let fills: Vec<(f64, f64)> = readings.iter()
+        .filter(|d| last_refill.map(|t| d.time >= t).unwrap_or(true))
+        .filter_map(|d| d.tank_fill.map(|f| (d.time as f64, f as f64)))
+        .collect();
+    let fill = fills.last().map(|(_, f)| *f as f32);
+
+    let n = fills.len() as f64;
+    let (mean_t, mean_f) = fills.iter().fold((0.0, 0.0), |(t, f), (ti, fi)| (t + ti / n, f + fi / n));
+    let var = fills.iter().map(|(t, _)| (t - mean_t).powi(2)).sum::<f64>();
+    let cov = fills.iter().map(|(t, f)| (t - mean_t) * (f - mean_f)).sum::<f64>();
+
+    if fills.len() < 2 || var == 0.0 {
+        return TankPrediction { fill, rate: None, empty_at: None, last_refill };
+    }
+
+    let rate = -cov / var;
+    let empty_at = match fills.last() {
+        Some((t, f)) if rate > 0.0 => Some((t + f.max(0.0) / rate) as usize),
+        _ => None
+    };
+
+    TankPrediction {
+        fill,
+        rate: Some((rate * 86400.0) as f32),
+        empty_at,
+        last_refill
+    }
+}
+
+// Readings flagged as faulty are left out.
+pub fn predict_station(station: usize, db: &mut PooledConn) -> Result<TankPrediction, Status> {
+    let since = now().saturating_sub(PREDICTION_WINDOW);
+    let mut readings = get_data_since(station, since, db)?;
+    anomalies::exclude_faults(&mut readings, &get_data_faults_since(station, since, db)?);
+    Ok(predict(&readings))
+}
+
+// Returns when the tank of the reading's station is predicted to run empty.
+// Predicting takes a week of readings, so the last prediction is reused until
+// it is `PREDICTION_TTL` old or the reading is a refill.
+pub fn predict_empty_at(reading: &DataRow, db: &mut PooledConn) -> Result<Option<usize>, Status> {
+    let refilled = match (get_data_before(reading.station, reading.time, db)?.and_then(|d| d.tank_fill), reading.tank_fill) {
+        (Some(before), Some(after)) => after - before >= REFILL_RISE,
+        _ => false
+    };
+
+    match get_tank_prediction(reading.station, db)? {
+        Some(p) if !refilled && now() < p.computed + PREDICTION_TTL => Ok(p.empty_at),
+        _ => {
+            let empty_at = predict_station(reading.station, db)?.empty_at;
+            set_tank_prediction(&TankPredictionRow { station: reading.station, computed: now(), empty_at }, db)?;
+            Ok(empty_at)
+        }
+    }
+}
+
+fn stats(readings: &[&DataRow], metric: Metric) -> Option<Stats> {
+    let values: Vec<f32> = readings.iter().filter_map(|d| metric.value(d)).collect();
+    if values.is_empty() {
+        return None;
+    }
+
+    Some(Stats {
+        min: values.iter().cloned().fold(f32::INFINITY, f32::min),
+        max: values.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+        mean: values.iter().sum::<f32>() / values.len() as f32
+    })
+}
+
+// Buckets readings by their local date in `tz`, days without readings are
+// left out. `readings` must be sorted by time.
+pub fn daily(readings: &[DataRow], tz: Tz) -> Vec<Day> {
+    let mut buckets: Vec<(NaiveDate, Vec<&DataRow>)> = Vec::new();
+    for d in readings.iter() {
+        let date = tz.timestamp(d.time as i64, 0).date().naive_local();
+        match buckets.last_mut() {
+            Some((last, bucket)) if *last == date => bucket.push(d),
+            _ => buckets.push((date, vec![d]))
+        }
+    }
+
+    buckets.into_iter().map(|(date, bucket)| Day {
+        date,
+        count: bucket.len(),
+        moisture: stats(&bucket, Metric::Moisture),
+        temperature: stats(&bucket, Metric::Temperature),
+        humidity: stats(&bucket, Metric::Humidity),
+        tank_fill: stats(&bucket, Metric::TankFill)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(time: usize, tank_fill: f32) -> DataRow {
+        DataRow {
+            station: 1,
+            time,
+            moisture: None,
+            temperature: None,
+            humidity: None,
+            tank_fill: Some(tank_fill),
+            moisture_raw: None,
+            temperature_raw: None,
+            humidity_raw: None,
+            tank_fill_raw: None
+        }
+    }
+
+    #[test]
+    fn detects_refills() {
+        let mut readings = vec![reading(0, 50.0), reading(60, 45.0), reading(120, 54.0), reading(180, 90.0)];
+        readings.insert(2, DataRow { tank_fill: None, ..reading(90, 0.0) });
+        let refills = refills(&readings);
+
+        assert_eq!(refills.len(), 1);
+        assert_eq!(refills[0].time, 180);
+        assert_eq!(refills[0].from, 54.0);
+        assert_eq!(refills[0].to, 90.0);
+    }
+
+    #[test]
+    fn predicts_nothing_without_a_drain() {
+        let prediction = predict(&[reading(0, 50.0)]);
+        assert_eq!(prediction.fill, Some(50.0));
+        assert!(prediction.rate.is_none());
+        assert!(prediction.empty_at.is_none());
+
+        let prediction = predict(&[reading(0, 50.0), reading(3600, 50.0), reading(7200, 55.0)]);
+        assert!(prediction.rate.unwrap() < 0.0);
+        assert!(prediction.empty_at.is_none());
+        assert!(prediction.last_refill.is_none());
+    }
+
+    #[test]
+    fn predicts_when_a_draining_tank_runs_empty() {
+        let prediction = predict(&[reading(0, 100.0), reading(43200, 95.0), reading(86400, 90.0)]);
+        assert_eq!(prediction.fill, Some(90.0));
+        assert!((prediction.rate.unwrap() - 10.0).abs() < 0.01);
+        assert!((prediction.empty_at.unwrap() as i64 - 10 * 86400).abs() <= 1);
+        assert!(prediction.last_refill.is_none());
+    }
+
+    #[test]
+    fn ignores_the_readings_before_the_last_refill() {
+        let readings = vec![reading(0, 50.0), reading(3600, 40.0), reading(7200, 30.0), reading(10800, 90.0), reading(14400, 80.0)];
+        let prediction = predict(&readings);
+
+        assert_eq!(prediction.last_refill, Some(10800));
+        assert_eq!(prediction.fill, Some(80.0));
+        assert!((prediction.rate.unwrap() - 240.0).abs() < 0.01);
+        assert!((prediction.empty_at.unwrap() as i64 - 43200).abs() <= 1);
+    }
+}
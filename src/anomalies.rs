@@ -0,0 +1,225 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use mysql::PooledConn;
+use rocket::http::Status;
+use serde::{Deserialize, Serialize};
+
+use crate::model::*;
+use crate::alerts::Metric;
+
+pub const SENSORS: &[Metric] = &[Metric::Moisture, Metric::Temperature, Metric::Humidity, Metric::TankFill];
+
+// A value that hasn't changed for this many seconds is considered stuck.
+const STUCK_WINDOW: usize = 6 * 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    OutOfRange,
+    Spike,
+    Stuck
+}
+
+impl Fault {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fault::OutOfRange => "out_of_range",
+            Fault::Spike => "spike",
+            Fault::Stuck => "stuck"
+        }
+    }
+}
+
+impl FromStr for Fault {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "out_of_range" => Ok(Fault::OutOfRange),
+            "spike" => Ok(Fault::Spike),
+            "stuck" => Ok(Fault::Stuck),
+            _ => Err(())
+        }
+    }
+}
+
+// Set with `stuck_window` in seconds, 0 turns the check off. Slow sensors with
+// a coarse resolution may need a longer window.
+pub fn stuck_window(conf: &HashMap<String, String>) -> usize {
+    conf.get("stuck_window").and_then(|s| s.parse().ok()).unwrap_or(STUCK_WINDOW)
+}
+
+// The physically possible values of a sensor.
+fn range(metric: Metric) -> (f32, f32) {
+    match metric {
+        Metric::Temperature => (-40.0, 85.0),
+        _ => (0.0, 100.0)
+    }
+}
+
+// The largest plausible change between two consecutive readings.
+fn max_step(metric: Metric) -> f32 {
+    match metric {
+        Metric::Temperature => 15.0,
+        _ => 30.0
+    }
+}
+
+// Sensors other than the thermometer only report percentages once they are
+// calibrated, the scale of their raw values is unknown.
+fn scaled(metric: Metric, calibrated: bool) -> bool {
+    metric == Metric::Temperature || calibrated
+}
+
+// `recent` are the station's latest readings, newest first, with `reading`
+// being the first of them. They reach back to before the start of the stuck
+// window if the station reported then. `calibrated` is whether the sensor has
+// a calibration.
+pub fn classify(metric: Metric, reading: &DataRow, recent: &[DataRow], calibrated: bool, stuck_window: usize) -> Option<Fault> {
+    let value = metric.value(reading)?;
+    let previous: Vec<(usize, f32)> = recent.iter().filter(|d| d.time <= reading.time).filter_map(|d| metric.value(d).map(|v| (d.time, v))).collect();
+
+    if scaled(metric, calibrated) {
+        let (min, max) = range(metric);
+        if value < min || value > max {
+            return Some(Fault::OutOfRange);
+        }
+
+        if let Some((_, last)) = previous.get(1) {
+            // The tank filling up or the soil getting wet quickly is a refill
+            // or watering, not a fault.
+            let step = match metric {
+                Metric::TankFill | Metric::Moisture => last - value,
+                _ => (value - last).abs()
+            };
+            if step > max_step(metric) {
+                return Some(Fault::Spike);
+            }
+        }
+    }
+
+    let unchanged_since = previous.iter().take_while(|(_, v)| *v == value).last().map(|(t, _)| *t).unwrap_or(reading.time);
+    if stuck_window > 0 && reading.time - unchanged_since >= stuck_window {
+        return Some(Fault::Stuck);
+    }
+
+    None
+}
+
+// Checks every sensor of a stored reading and records the faults found.
+pub fn check(reading: &DataRow, conf: &HashMap<String, String>, db: &mut PooledConn) -> Result<Vec<(Metric, Fault)>, Status> {
+    let stuck_window = stuck_window(conf);
+    let since = reading.time.saturating_sub(stuck_window);
+    let mut recent = get_data_since(reading.station, since, db)?;
+    recent.reverse();
+    recent.extend(get_data_before(reading.station, since, db)?);
+    let calibrations = get_calibrations(reading.station, db)?;
+    let faults: Vec<(Metric, Fault)> = SENSORS.iter().filter_map(|m| {
+        let calibrated = calibrations.iter().any(|c| c.metric == *m);
+        classify(*m, reading, &recent, calibrated, stuck_window).map(|f| (*m, f))
+    }).collect();
+
+    for (metric, fault) in faults.iter() {
+        add_data_fault(reading.station, reading.time, *metric, *fault, metric.value(reading).unwrap_or(0.0), db)?;
+    }
+
+    Ok(faults)
+}
+
+fn clear(metric: Metric, reading: &mut DataRow) {
+    match metric {
+        Metric::Moisture => reading.moisture = None,
+        Metric::Temperature => reading.temperature = None,
+        Metric::Humidity => reading.humidity = None,
+        Metric::TankFill => reading.tank_fill = None,
+        Metric::TankEmptyIn => ()
+    }
+}
+
+// The reading without the values of faulty sensors, so they don't fire alerts
+// or make the station water.
+pub fn without_faults(reading: &DataRow, faults: &[(Metric, Fault)]) -> DataRow {
+    let mut reading = reading.clone();
+    faults.iter().for_each(|(metric, _)| clear(*metric, &mut reading));
+    reading
+}
+
+// Removes the values that were recorded as faulty from stored readings.
+pub fn exclude_faults(readings: &mut [DataRow], faults: &[DataFaultRow]) {
+    for fault in faults.iter() {
+        readings.iter_mut().filter(|d| d.station == fault.station && d.time == fault.time).for_each(|d| clear(fault.metric, d));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(time: usize, moisture: f32) -> DataRow {
+        DataRow {
+            station: 1,
+            time,
+            moisture: Some(moisture),
+            temperature: None,
+            humidity: None,
+            tank_fill: None,
+            moisture_raw: None,
+            temperature_raw: None,
+            humidity_raw: None,
+            tank_fill_raw: None
+        }
+    }
+
+    fn classify_latest(recent: &[DataRow], calibrated: bool) -> Option<Fault> {
+        classify(Metric::Moisture, &recent[0], recent, calibrated, 3600)
+    }
+
+    #[test]
+    fn accepts_plausible_readings() {
+        assert_eq!(classify_latest(&[reading(120, 42.0), reading(60, 40.0), reading(0, 41.0)], true), None);
+    }
+
+    #[test]
+    fn checks_the_range_of_calibrated_sensors_only() {
+        assert_eq!(classify_latest(&[reading(0, 120.0)], true), Some(Fault::OutOfRange));
+        assert_eq!(classify_latest(&[reading(0, -1.0)], true), Some(Fault::OutOfRange));
+        assert_eq!(classify_latest(&[reading(0, 620.0)], false), None);
+
+        let temperature = DataRow { temperature: Some(120.0), ..reading(0, 40.0) };
+        assert_eq!(classify(Metric::Temperature, &temperature, &[temperature.clone()], false, 3600), Some(Fault::OutOfRange));
+    }
+
+    #[test]
+    fn treats_sudden_drops_in_moisture_as_spikes() {
+        assert_eq!(classify_latest(&[reading(60, 5.0), reading(0, 60.0)], true), Some(Fault::Spike));
+        assert_eq!(classify_latest(&[reading(60, 60.0), reading(0, 5.0)], true), None);
+        assert_eq!(classify_latest(&[reading(60, 400.0), reading(0, 900.0)], false), None);
+    }
+
+    #[test]
+    fn detects_stuck_sensors() {
+        let recent = [reading(3600, 40.0), reading(1800, 40.0), reading(0, 40.0)];
+        assert_eq!(classify_latest(&recent, true), Some(Fault::Stuck));
+        assert_eq!(classify(Metric::Moisture, &recent[0], &recent, true, 0), None);
+        assert_eq!(classify_latest(&recent[..2], true), None);
+    }
+}
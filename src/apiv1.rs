@@ -20,22 +20,129 @@ use std::collections::HashMap;
 use std::sync::{Mutex, Arc};
 use std::path::PathBuf;
 
-use mysql::Pool;
+use chrono::TimeZone;
+use chrono_tz::Tz;
+use mysql::{Pool, PooledConn, TxOpts};
+use mysql::prelude::Queryable;
 use openapi::v3_0::*;
 use rocket::{State, Request};
-use rocket::http::Status;
-use rocket::response::Redirect;
+use rocket::config::RocketConfig;
+use rocket::http::{Status, ContentType};
+use rocket::response::{Redirect, Stream};
+use rocket::response::content::Content;
 use rocket_contrib::json::Json;
+use serde_json::{json, Value};
 use uuid::Uuid;
 
 use crate::model::*;
+use crate::alerts::{Comparison, Metric};
+use crate::analytics;
+use crate::anomalies;
 use crate::auth::*;
+use crate::bus::Bus;
+use crate::schedule::{Plan, DEFAULT_TIMEZONE};
+use crate::state::*;
+use crate::station_conf::*;
+use crate::validation::*;
+use crate::email;
+use crate::encoding::*;
+use crate::events::*;
+use crate::ingest::*;
+use crate::webhooks;
+use crate::units::*;
 use crate::ws_notifier::*;
+use crate::ws_listener::ListenMode;
+use crate::http_mux;
+use crate::plants;
 
 type ApiResp<T> = Result<Json<T>, Status>;
 type DbConn = Arc<Mutex<Pool>>;
 type Conf = HashMap<String, String>;
-type WsRequests = Arc<Mutex<Vec<WsRequest>>>;
+type WsRequests = Arc<Mutex<Box<dyn Bus>>>;
+type Events = Arc<Mutex<EventLog>>;
+
+const HISTORY_PAGE: usize = 50;
+const HISTORY_PAGE_MAX: usize = 500;
+const PREVIEW_COUNT: usize = 10;
+const PREVIEW_COUNT_MAX: usize = 100;
+const SCHEDULE_RUNS: usize = 50;
+const ALERTS_MAX: usize = 100;
+const DELIVERIES_MAX: usize = 100;
+const OUTAGES_MAX: usize = 100;
+const REFILL_DAYS: usize = 30;
+const REFILL_DAYS_MAX: usize = 365;
+const HEALTH_WINDOW: usize = 86400;
+const FAULTS_MAX: usize = 100;
+const DAILY_DAYS: usize = 30;
+const DAILY_DAYS_MAX: usize = 365;
+
+fn push_event(events: &Events, station: usize, kind: EventKind, data: String) -> Result<(), Status> {
+    events.lock().or(Err(Status::InternalServerError))?.push(station, kind, data);
+    Ok(())
+}
+
+// Validates a new configuration against the schema published by the station
+// and records it as a new revision. The event is pushed by `save_station`.
+fn change_conf(station: &mut StationRow, conf: Value, actor: Actor, login: Option<&str>, source: &str, db: &mut PooledConn, unprocessable: &Unprocessable) -> Result<u64, Status> {
+    check_conf(station, &conf, unprocessable)?;
+    record_conf(station, conf, actor, login, source, db)
+}
+
+fn check_conf(station: &StationRow, conf: &Value, unprocessable: &Unprocessable) -> Result<(), Status> {
+    let errors = validate(station.schema.as_ref(), conf)?;
+    if !errors.is_empty() {
+        return Err(unprocessable.fields(errors));
+    }
+    Ok(())
+}
+
+fn record_conf(station: &mut StationRow, conf: Value, actor: Actor, login: Option<&str>, source: &str, db: &mut impl Queryable) -> Result<u64, Status> {
+    let revision = add_conf_revision(station.id, actor, login, &conf, db)?;
+    let old = station.conf.as_ref().map(Value::to_string);
+    add_history(station.id, actor, login, "conf", old.as_deref(), Some(&conf.to_string()), source, db)?;
+    station.conf = Some(conf);
+    Ok(revision)
+}
+
+// Writes the station and only then pushes the event for a new configuration
+// revision, so that subscribers never see a change that wasn't stored.
+fn save_station(station: StationRow, revision: Option<u64>, db: &mut PooledConn, events: &Events) -> Result<(), Status> {
+    let id = station.id;
+    let conf = station.conf.clone();
+    update_station(station, db)?;
+    if let Some(revision) = revision {
+        push_event(events, id, EventKind::Conf, json!({ "conf": conf, "revision": revision }).to_string())?;
+    }
+    Ok(())
+}
+
+fn publish_conf(ws_reqs: &WsRequests, station: &StationRow, revision: u64) -> Result<(), Status> {
+    ws_reqs.lock().or(Err(Status::InternalServerError))?.publish(WsRequest::UpdateConf(WsUpdateConf {
+        id: station.id,
+        conf: station.conf.clone().unwrap_or(json!({})),
+        revision
+    }))
+}
+
+// Changes the state of a station on behalf of its owner and notifies it.
+fn change_state(mut station: StationRow, state: StationState, login: &str, source: &str, db: &mut PooledConn, ws_reqs: &WsRequests, events: &Events, unprocessable: &Unprocessable) -> Result<(), Status> {
+    if !station.state.can_transition(state, Actor::User) {
+        return Err(unprocessable.field("state", &format!("can't change from {} to {}", station.state, state)));
+    }
+
+    add_history(station.id, Actor::User, Some(login), "state", Some(station.state.as_str()), Some(state.as_str()), source, db)?;
+
+    let id = station.id;
+    station.state = state;
+    station.unknown_state = None;
+    update_station(station, db)?;
+
+    ws_reqs.lock().or(Err(Status::InternalServerError))?.publish(WsRequest::UpdateState(WsUpdateState {
+        id,
+        state
+    }))?;
+    push_event(events, id, EventKind::State, json!({ "state": state }).to_string())
+}
 
 #[get("/")]
 fn index() -> Redirect {
@@ -62,8 +169,36 @@ fn root() -> Json<Spec> {
     })
 }
 
+fn plant_element(p: PlantRow) -> PlantElement {
+    PlantElement {
+        id: p.id,
+        name: p.name,
+        species: p.species,
+        moisture: p.moisture,
+        temperature: p.temperature,
+        humidity: p.humidity,
+        watering: p.watering
+    }
+}
+
+#[get("/v1/plants")]
+fn plants_get(db: State<DbConn>) -> ApiResp<PlantsResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+
+    Ok(Json(PlantsResp {
+        plants: get_plants(&mut db)?.into_iter().map(plant_element).collect()
+    }))
+}
+
+#[get("/v1/plants/<plant>")]
+fn plant_get(plant: String, db: State<DbConn>) -> ApiResp<PlantElement> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+
+    Ok(Json(plant_element(get_plant(&plant, &mut db)?.ok_or(Status::NotFound)?)))
+}
+
 #[post("/v1/stations", data = "<req>")]
-fn stations_post(req: Json<StationsReq>, db: State<DbConn>) -> ApiResp<StationsResp> {
+fn stations_post(req: Validated<StationsReq>, db: State<DbConn>) -> ApiResp<StationsResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
 
     if get_station(req.id, &mut db).is_err() {
@@ -84,18 +219,80 @@ fn station_get(id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<Station
     let station = get_station(id, &mut db)?;
 
     if auth.verify(&station.token) {
-        Ok(Json(StationResp {
-            name: station.name,
-            owner: station.owner,
-            conf: station.conf.unwrap_or("{}".to_string())
-        }))
+        let meta = get_station_meta(station.id, &mut db)?;
+        Ok(Json(station_resp(station, meta)))
     } else {
         Err(Status::Unauthorized)
     }
 }
 
+fn station_resp(station: StationRow, meta: StationMetaRow) -> StationResp {
+    let conf = station.conf.unwrap_or(json!({}));
+    StationResp {
+        name: station.name,
+        owner: station.owner,
+        conf: conf_string(&conf),
+        conf_json: conf,
+        schema: station.schema,
+        tank_capacity: station.tank_capacity,
+        plant: station.plant,
+        location: meta.location,
+        latitude: meta.latitude,
+        longitude: meta.longitude,
+        timezone: meta.timezone,
+        indoor: meta.indoor,
+        notes: meta.notes,
+        photo: meta.photo
+    }
+}
+
+// `conf` is the string older clients send, `conf_json` the configuration
+// itself. Strings are read like stored legacy configurations.
+fn requested_conf(req: &StationReq) -> Option<Value> {
+    req.conf_json.clone().or_else(|| req.conf.clone().map(parse_legacy_conf))
+}
+
+// Text fields are cleared by an empty string.
+fn change_meta(meta: &mut StationMetaRow, req: &StationReq) {
+    let text = |field: &mut Option<String>, value: &Option<String>| {
+        if let Some(value) = value {
+            *field = Some(value.clone()).filter(|v| !v.is_empty());
+        }
+    };
+    text(&mut meta.location, &req.location);
+    text(&mut meta.timezone, &req.timezone);
+    text(&mut meta.notes, &req.notes);
+    text(&mut meta.photo, &req.photo);
+
+    // `null` clears the coordinates and indoor flag.
+    if let (Some(latitude), Some(longitude)) = (req.latitude, req.longitude) {
+        meta.latitude = latitude;
+        meta.longitude = longitude;
+    }
+    if let Some(indoor) = req.indoor {
+        meta.indoor = indoor;
+    }
+}
+
+// An empty id detaches the plant profile.
+fn station_plant(plant: &str, db: &mut PooledConn, unprocessable: &Unprocessable) -> Result<Option<String>, Status> {
+    if plant.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(get_plant(plant, db)?.ok_or_else(|| unprocessable.field("plant", "unknown plant profile"))?.id))
+    }
+}
+
+// The range the station's plant profile recommends for a metric.
+fn plant_range(station: &StationRow, metric: Metric, db: &mut PooledConn) -> Result<Option<(f32, f32)>, Status> {
+    match station.plant.as_ref() {
+        Some(plant) => Ok(get_plant(plant, db)?.and_then(|p| plants::range(&p, metric))),
+        None => Ok(None)
+    }
+}
+
 #[put("/v1/stations/<id>", data = "<req>")]
-fn station_put(id: usize, req: Json<StationReq>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+fn station_put(id: usize, req: Validated<StationReq>, db: State<DbConn>, events: State<Events>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<EmptyResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
     let mut station = get_station(id, &mut db)?;
 
@@ -103,10 +300,23 @@ fn station_put(id: usize, req: Json<StationReq>, db: State<DbConn>, auth: BasicA
         if let Some(name) = req.name.clone() {
             station.name = name;
         }
-        if let Some(conf) = req.conf.clone() {
-            station.conf = Some(conf);
+        if let Some(capacity) = req.tank_capacity {
+            station.tank_capacity = Some(capacity);
         }
-        update_station(station, &mut db)?;
+        if let Some(plant) = req.plant.as_ref() {
+            station.plant = station_plant(plant, &mut db, &unprocessable)?;
+        }
+        if let Some(schema) = req.schema.clone() {
+            station.schema = Some(schema);
+        }
+        let mut revision = None;
+        if let Some(conf) = requested_conf(&req) {
+            // The station already runs the configuration it reports.
+            let new = change_conf(&mut station, conf, Actor::Station, None, "PUT /v1/stations/<id>", &mut db, &unprocessable)?;
+            set_conf_acked(station.id, new, &mut db)?;
+            revision = Some(new);
+        }
+        save_station(station, revision, &mut db, &events)?;
         Ok(Json(EmptyResp {}))
     } else {
         Err(Status::Unauthorized)
@@ -114,12 +324,12 @@ fn station_put(id: usize, req: Json<StationReq>, db: State<DbConn>, auth: BasicA
 }
 
 #[post("/v1/stations/<id>/data", data = "<req>")]
-fn data_post(id: usize, req: Json<DataReq>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+fn data_post(id: usize, req: Encoded<DataReq>, db: State<DbConn>, conf: State<Conf>, ws_reqs: State<WsRequests>, events: State<Events>, auth: BasicAuth) -> ApiResp<EmptyResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
     let station = get_station(id, &mut db)?;
 
     if auth.verify(&station.token) {
-        add_data(station.id, req.moisture, req.temperature, req.humidity, req.tank_fill, &mut db)?;
+        ingest(station.id, &req, &conf, &mut db, &ws_reqs, &events)?;
         Ok(Json(EmptyResp {}))
     } else {
         Err(Status::Unauthorized)
@@ -141,13 +351,21 @@ fn state_get(id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<StateResp
 }
 
 #[put("/v1/stations/<id>/state", data = "<req>")]
-fn state_put(id: usize, req: Json<StateReq>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+fn state_put(id: usize, req: Validated<StateReq>, db: State<DbConn>, events: State<Events>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<EmptyResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
     let mut station = get_station(id, &mut db)?;
 
     if auth.verify(&station.token) {
-        station.state = req.state.clone();
+        if !station.state.can_transition(req.state, Actor::Station) {
+            return Err(unprocessable.field("state", &format!("can't change from {} to {}", station.state, req.state)));
+        }
+
+        add_history(station.id, Actor::Station, None, "state", Some(station.state.as_str()), Some(req.state.as_str()), "PUT /v1/stations/<id>/state", &mut db)?;
+        let id = station.id;
+        station.state = req.state;
+        station.unknown_state = None;
         update_station(station, &mut db)?;
+        push_event(&events, id, EventKind::State, json!({ "state": req.state }).to_string())?;
         Ok(Json(EmptyResp {}))
     } else {
         Err(Status::Unauthorized)
@@ -155,12 +373,12 @@ fn state_put(id: usize, req: Json<StateReq>, db: State<DbConn>, auth: BasicAuth)
 }
 
 #[post("/v1/users", data = "<req>")]
-fn users_post(req: Json<UsersReq>, db: State<DbConn>) -> ApiResp<EmptyResp> {
+fn users_post(req: Validated<UsersReq>, db: State<DbConn>) -> ApiResp<EmptyResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
 
     if get_user(&req.login, &mut db).is_err() {
         let hash = BasicAuth::from_parts(&req.login, &req.pass).hash();
-        add_user(&req.login, &req.name, &hash, &mut db)?;
+        add_user(&req.login, &req.name, &hash, req.email.as_deref(), &mut db)?;
         Ok(Json(EmptyResp {}))
     } else {
         Err(Status::Conflict)
@@ -174,7 +392,8 @@ fn user_get(login: String, db: State<DbConn>, auth: BasicAuth) -> ApiResp<UserRe
 
     if auth.verify(&user.pass) {
         Ok(Json(UserResp {
-            name: user.name
+            name: user.name,
+            email: user.email
         }))
     } else {
         Err(Status::Unauthorized)
@@ -182,7 +401,7 @@ fn user_get(login: String, db: State<DbConn>, auth: BasicAuth) -> ApiResp<UserRe
 }
 
 #[put("/v1/users/<login>", data = "<req>")]
-fn user_put(login: String, req: Json<UserReq>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+fn user_put(login: String, req: Validated<UserReq>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
     let mut user = get_user(&login, &mut db)?;
 
@@ -190,6 +409,10 @@ fn user_put(login: String, req: Json<UserReq>, db: State<DbConn>, auth: BasicAut
         let pass_hash = BasicAuth::from_parts(&user.login, &req.pass).hash();
         user.pass = pass_hash;
         user.name = req.name.clone();
+        // An empty address removes it.
+        if let Some(email) = req.email.clone() {
+            user.email = Some(email).filter(|e| !e.is_empty());
+        }
         update_user(user, &mut db)?;
         Ok(Json(EmptyResp {}))
     } else {
@@ -215,181 +438,1391 @@ fn user_delete(login: String, db: State<DbConn>, auth: BasicAuth) -> ApiResp<Emp
     }
 }
 
-#[get("/v1/users/<login>/stations")]
-fn user_stations_get(login: String, db: State<DbConn>, auth: BasicAuth) -> ApiResp<UserStationsResp> {
+#[get("/v1/users/<login>/notifications")]
+fn user_notifications_get(login: String, db: State<DbConn>, auth: BasicAuth) -> ApiResp<NotificationsResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
     let user = get_user(&login, &mut db)?;
 
     if auth.verify(&user.pass) {
-        let stations = get_stations(&login, &mut db)?;
-        Ok(Json(UserStationsResp {
-            stations: stations.into_iter().map(|s| s.id).collect()
+        Ok(Json(match get_notification_prefs(&user.login, &mut db)? {
+            Some(prefs) => NotificationsResp {
+                events: prefs.events,
+                digest: prefs.digest,
+                digest_interval: prefs.digest_interval
+            },
+            None => NotificationsResp {
+                events: email::DEFAULT_EVENTS.iter().map(|e| e.to_string()).collect(),
+                digest: false,
+                digest_interval: email::DEFAULT_DIGEST_INTERVAL
+            }
         }))
     } else {
         Err(Status::Unauthorized)
     }
 }
 
-#[post("/v1/users/<login>/stations", data = "<req>")]
-fn user_stations_post(login: String, req: Json<UserStationsReq>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+#[put("/v1/users/<login>/notifications", data = "<req>")]
+fn user_notifications_put(login: String, req: Validated<NotificationsReq>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
     let user = get_user(&login, &mut db)?;
 
     if auth.verify(&user.pass) {
-        let mut station = get_station(req.id, &mut db)?;
-        if station.owner.is_none() {
-            station.owner = Some(user.login);
-            update_station(station, &mut db)?;
-        }
+        let events = req.events.clone().unwrap_or(email::DEFAULT_EVENTS.iter().map(|e| e.to_string()).collect());
+        update_notification_prefs(&NotificationPrefsRow {
+            login: user.login,
+            events,
+            digest: req.digest.unwrap_or(false),
+            digest_interval: req.digest_interval.unwrap_or(email::DEFAULT_DIGEST_INTERVAL)
+        }, &mut db)?;
         Ok(Json(EmptyResp {}))
     } else {
         Err(Status::Unauthorized)
     }
 }
 
-#[get("/v1/users/<login>/stations/<id>")]
-fn user_station_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<StationResp> {
+fn user_webhook(user: &UserRow, webhook: u64, db: &mut PooledConn) -> Result<WebhookRow, Status> {
+    let webhook = get_webhook(webhook, db)?;
+    if webhook.login == user.login {
+        Ok(webhook)
+    } else {
+        Err(Status::NotFound)
+    }
+}
+
+fn webhook_element(w: WebhookRow) -> WebhookElement {
+    WebhookElement {
+        id: w.id,
+        url: w.url,
+        events: w.events,
+        secret: None,
+        enabled: w.enabled
+    }
+}
+
+#[get("/v1/users/<login>/units")]
+fn user_units_get(login: String, db: State<DbConn>, auth: BasicAuth) -> ApiResp<UnitsResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
     let user = get_user(&login, &mut db)?;
-    let station = get_station(id, &mut db)?;
 
-    if station.owner == Some(user.login) && auth.verify(&user.pass) {
-        Ok(Json(StationResp {
-            name: station.name,
-            owner: station.owner,
-            conf: station.conf.unwrap_or("{}".to_string())
+    if auth.verify(&user.pass) {
+        let prefs = get_unit_prefs(&user.login, &mut db)?;
+        Ok(Json(UnitsResp {
+            temperature: prefs.as_ref().map(|p| p.temperature).unwrap_or(TemperatureUnit::Celsius),
+            tank_fill: prefs.map(|p| p.tank_fill).unwrap_or(TankUnit::Percent)
         }))
     } else {
         Err(Status::Unauthorized)
     }
 }
 
-#[put("/v1/users/<login>/stations/<id>", data = "<req>")]
-fn user_station_put(login: String, id: usize, req: Json<StationReq>, db: State<DbConn>, ws_reqs: State<WsRequests>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+#[put("/v1/users/<login>/units", data = "<req>")]
+fn user_units_put(login: String, req: Validated<UnitsReq>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
     let user = get_user(&login, &mut db)?;
-    let mut station = get_station(id, &mut db)?;
 
-    if station.owner == Some(user.login) && auth.verify(&user.pass) {
-        if let Some(name) = req.name.clone() {
-            station.name = name;
-        }
-        if let Some(conf) = req.conf.clone() {
-            station.conf = Some(conf.clone());
-            let mut ws_reqs = ws_reqs.lock().or(Err(Status::InternalServerError))?;
-            ws_reqs.push(WsRequest::UpdateConf(WsUpdateConf {
-                id: station.id,
-                conf
-            }));
+    if auth.verify(&user.pass) {
+        update_unit_prefs(&UnitPrefsRow {
+            login: user.login,
+            temperature: req.temperature.unwrap_or(TemperatureUnit::Celsius),
+            tank_fill: req.tank_fill.unwrap_or(TankUnit::Percent)
+        }, &mut db)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+fn user_garden(user: &UserRow, garden: u64, db: &mut PooledConn) -> Result<GardenRow, Status> {
+    let garden = get_garden(garden, db)?;
+    if garden.login == user.login {
+        Ok(garden)
+    } else {
+        Err(Status::NotFound)
+    }
+}
+
+fn garden_element(g: GardenRow) -> GardenElement {
+    GardenElement {
+        id: g.id,
+        name: g.name,
+        stations: g.stations
+    }
+}
+
+// Gardens can only contain stations of their owner.
+fn owned_stations(user: &UserRow, stations: &[usize], db: &mut PooledConn, unprocessable: &Unprocessable) -> Result<Vec<usize>, Status> {
+    let mut stations = stations.to_vec();
+    stations.sort_unstable();
+    stations.dedup();
+    for id in stations.iter() {
+        match get_station(*id, db) {
+            Ok(station) if station.owner.as_ref() == Some(&user.login) => (),
+            Ok(_) | Err(Status::NotFound) => return Err(unprocessable.field("stations", "must only contain stations you own")),
+            Err(e) => return Err(e)
         }
-        update_station(station, &mut db)?;
+    }
+    Ok(stations)
+}
+
+// Runs a bulk action on every station of a garden that still belongs to its
+// owner. A failing station doesn't stop the others.
+fn garden_bulk<F>(garden: &GardenRow, db: &mut PooledConn, mut action: F) -> BulkResp
+    where F: FnMut(StationRow, &mut PooledConn) -> Result<(), Status>
+{
+    let mut results = Vec::new();
+    for id in garden.stations.iter() {
+        let res = match get_station(*id, db) {
+            Ok(station) if station.owner.as_ref() == Some(&garden.login) => action(station, db),
+            Ok(_) => Err(Status::NotFound),
+            Err(e) => Err(e)
+        };
+        results.push(BulkElement {
+            station: *id,
+            status: res.err().unwrap_or(Status::Ok).code
+        });
+    }
+    BulkResp { stations: results }
+}
+
+#[get("/v1/users/<login>/gardens")]
+fn user_gardens_get(login: String, db: State<DbConn>, auth: BasicAuth) -> ApiResp<GardensResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+
+    if auth.verify(&user.pass) {
+        Ok(Json(GardensResp {
+            gardens: get_gardens(&user.login, &mut db)?.into_iter().map(garden_element).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[post("/v1/users/<login>/gardens", data = "<req>")]
+fn user_gardens_post(login: String, req: Validated<GardenReq>, db: State<DbConn>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<GardenCreatedResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+
+    if auth.verify(&user.pass) {
+        let stations = owned_stations(&user, &req.stations, &mut db, &unprocessable)?;
+        Ok(Json(GardenCreatedResp {
+            id: add_garden(&GardenRow {
+                id: 0,
+                login: user.login,
+                name: req.name.clone(),
+                stations
+            }, &mut db)?
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/gardens/<garden>")]
+fn user_garden_get(login: String, garden: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<GardenElement> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+
+    if auth.verify(&user.pass) {
+        Ok(Json(garden_element(user_garden(&user, garden, &mut db)?)))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[put("/v1/users/<login>/gardens/<garden>", data = "<req>")]
+fn user_garden_put(login: String, garden: u64, req: Validated<GardenReq>, db: State<DbConn>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+
+    if auth.verify(&user.pass) {
+        let mut garden = user_garden(&user, garden, &mut db)?;
+        garden.name = req.name.clone();
+        garden.stations = owned_stations(&user, &req.stations, &mut db, &unprocessable)?;
+        update_garden(&garden, &mut db)?;
         Ok(Json(EmptyResp {}))
     } else {
         Err(Status::Unauthorized)
     }
 }
 
-#[delete("/v1/users/<login>/stations/<id>")]
-fn user_station_delete(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+#[delete("/v1/users/<login>/gardens/<garden>")]
+fn user_garden_delete(login: String, garden: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
     let user = get_user(&login, &mut db)?;
-    let mut station = get_station(id, &mut db)?;
 
-    if station.owner == Some(user.login) && auth.verify(&user.pass) {
-        station.owner = None;
-        update_station(station, &mut db)?;
+    if auth.verify(&user.pass) {
+        let garden = user_garden(&user, garden, &mut db)?;
+        delete_garden(garden.id, &mut db)?;
         Ok(Json(EmptyResp {}))
     } else {
         Err(Status::Unauthorized)
     }
 }
 
-#[get("/v1/users/<login>/stations/<id>/data?<count>")]
-fn user_data_get(login: String, id: usize, count: Option<usize>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<DataResp> {
+// The latest reading of every station in the garden.
+#[get("/v1/users/<login>/gardens/<garden>/data")]
+fn user_garden_data_get(login: String, garden: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<GardenDataResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
     let user = get_user(&login, &mut db)?;
-    let station = get_station(id, &mut db)?;
 
-    if station.owner == Some(user.login) && auth.verify(&user.pass) {
-        let data = if let Some(count) = count {
-            get_data_count(station.id, count, &mut db)?
-        } else {
-            get_data(station.id, &mut db)?
-        };
+    if auth.verify(&user.pass) {
+        let garden = user_garden(&user, garden, &mut db)?;
+        let prefs = get_unit_prefs(&user.login, &mut db)?;
 
-        Ok(Json(DataResp {
-            data: data.into_iter().map(|d| DataElement {
-                time: d.time,
-                moisture: d.moisture,
-                temperature: d.temperature,
-                humidity: d.humidity,
-                tank_fill: d.tank_fill
-            }).collect()
+        let mut stations = Vec::new();
+        for id in garden.stations.iter() {
+            let station = match get_station(*id, &mut db) {
+                Ok(station) if station.owner.as_ref() == Some(&user.login) => station,
+                _ => continue
+            };
+            let units = Units::new(prefs.as_ref(), station.tank_capacity);
+            stations.push(GardenDataElement {
+                station: station.id,
+                name: station.name,
+                state: station.state,
+                units: UnitsResp {
+                    temperature: units.temperature,
+                    tank_fill: units.tank_fill
+                },
+                data: get_data_count(station.id, 1, &mut db)?.first().map(|d| units.element(d))
+            });
+        }
+
+        Ok(Json(GardenDataResp {
+            stations
         }))
     } else {
         Err(Status::Unauthorized)
     }
 }
 
-#[get("/v1/users/<login>/stations/<id>/state")]
-fn user_state_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<StateResp> {
+#[put("/v1/users/<login>/gardens/<garden>/state", data = "<req>")]
+fn user_garden_state_put(login: String, garden: u64, req: Validated<StateReq>, db: State<DbConn>, ws_reqs: State<WsRequests>, events: State<Events>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<BulkResp> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
     let user = get_user(&login, &mut db)?;
-    let station = get_station(id, &mut db)?;
 
-    if station.owner == Some(user.login) && auth.verify(&user.pass) {
-        Ok(Json(StateResp {
-            state: station.state
+    if auth.verify(&user.pass) {
+        let garden = user_garden(&user, garden, &mut db)?;
+        Ok(Json(garden_bulk(&garden, &mut db, |station, db| {
+            change_state(station, req.state, &login, "PUT /v1/users/<login>/gardens/<garden>/state", db, &ws_reqs, &events, &unprocessable)
+        })))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+// Replaces the configuration of every station in the garden, each one is
+// validated against its own station's schema.
+#[put("/v1/users/<login>/gardens/<garden>/conf", data = "<req>")]
+fn user_garden_conf_put(login: String, garden: u64, req: Json<Value>, db: State<DbConn>, ws_reqs: State<WsRequests>, events: State<Events>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<BulkResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+
+    if auth.verify(&user.pass) {
+        let garden = user_garden(&user, garden, &mut db)?;
+        let conf = req.into_inner();
+        Ok(Json(garden_bulk(&garden, &mut db, |mut station, db| {
+            let revision = change_conf(&mut station, conf.clone(), Actor::User, Some(&login), "PUT /v1/users/<login>/gardens/<garden>/conf", db, &unprocessable)?;
+            publish_conf(&ws_reqs, &station, revision)?;
+            save_station(station, Some(revision), db, &events)
+        })))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/webhooks")]
+fn user_webhooks_get(login: String, db: State<DbConn>, auth: BasicAuth) -> ApiResp<WebhooksResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+
+    if auth.verify(&user.pass) {
+        Ok(Json(WebhooksResp {
+            webhooks: get_webhooks(&user.login, &mut db)?.into_iter().map(webhook_element).collect()
         }))
     } else {
         Err(Status::Unauthorized)
     }
 }
 
-#[put("/v1/users/<login>/stations/<id>/state", data = "<req>")]
-fn user_state_put(login: String, id: usize, req: Json<StateReq>, db: State<DbConn>, ws_reqs: State<WsRequests>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+// The secret is generated unless the request provides one. This is the only
+// response that includes it.
+#[post("/v1/users/<login>/webhooks", data = "<req>")]
+fn user_webhooks_post(login: String, req: Validated<WebhookReq>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<WebhookElement> {
     let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
     let user = get_user(&login, &mut db)?;
-    let mut station = get_station(id, &mut db)?;
 
-    if station.owner == Some(user.login) && auth.verify(&user.pass) {
-        let mut ws_reqs = ws_reqs.lock().or(Err(Status::InternalServerError))?;
-        ws_reqs.push(WsRequest::UpdateState(WsUpdateState {
-            id: station.id,
-            state: req.state.clone()
-        }));
+    if auth.verify(&user.pass) {
+        let mut webhook = WebhookRow {
+            id: 0,
+            login: user.login,
+            url: req.url.clone(),
+            events: req.events.clone().unwrap_or_default(),
+            secret: req.secret.clone().unwrap_or_else(|| Uuid::new_v4().to_simple().to_string()),
+            enabled: req.enabled.unwrap_or(true)
+        };
+        webhook.id = add_webhook(&webhook, &mut db)?;
+        let secret = webhook.secret.clone();
+        Ok(Json(WebhookElement { secret: Some(secret), ..webhook_element(webhook) }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
 
-        station.state = req.state.clone();
-        update_station(station, &mut db)?;
+#[get("/v1/users/<login>/webhooks/<webhook>")]
+fn user_webhook_get(login: String, webhook: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<WebhookElement> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+
+    if auth.verify(&user.pass) {
+        Ok(Json(webhook_element(user_webhook(&user, webhook, &mut db)?)))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[put("/v1/users/<login>/webhooks/<webhook>", data = "<req>")]
+fn user_webhook_put(login: String, webhook: u64, req: Validated<WebhookReq>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
 
+    if auth.verify(&user.pass) {
+        let mut webhook = user_webhook(&user, webhook, &mut db)?;
+        webhook.url = req.url.clone();
+        if let Some(events) = req.events.clone() {
+            webhook.events = events;
+        }
+        if let Some(secret) = req.secret.clone() {
+            webhook.secret = secret;
+        }
+        if let Some(enabled) = req.enabled {
+            webhook.enabled = enabled;
+        }
+        update_webhook(&webhook, &mut db)?;
         Ok(Json(EmptyResp {}))
     } else {
         Err(Status::Unauthorized)
     }
 }
 
+#[delete("/v1/users/<login>/webhooks/<webhook>")]
+fn user_webhook_delete(login: String, webhook: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
 
-#[catch(400)] 
-fn bad_request(_req: &Request) {}
+    if auth.verify(&user.pass) {
+        let webhook = user_webhook(&user, webhook, &mut db)?;
+        delete_webhook(webhook.id, &mut db)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
 
-#[catch(401)] 
-fn unauthorised(_req: &Request) {}
+#[get("/v1/users/<login>/webhooks/<webhook>/deliveries")]
+fn user_webhook_deliveries_get(login: String, webhook: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<WebhookDeliveriesResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
 
-#[catch(404)] 
-fn not_found(_req: &Request) {}
+    if auth.verify(&user.pass) {
+        let webhook = user_webhook(&user, webhook, &mut db)?;
+        Ok(Json(WebhookDeliveriesResp {
+            deliveries: get_webhook_deliveries(webhook.id, DELIVERIES_MAX, &mut db)?.into_iter().map(|d| WebhookDeliveryElement {
+                event: d.event,
+                kind: d.kind,
+                time: d.time,
+                attempt: d.attempt,
+                status: d.status,
+                error: d.error
+            }).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
 
-#[catch(409)] 
-fn conflict(_req: &Request) {}
+// Queues a `ping` event, which is sent once, even if the webhook is disabled.
+// The outcome shows up in the deliveries.
+#[post("/v1/users/<login>/webhooks/<webhook>/test")]
+fn user_webhook_test_post(login: String, webhook: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<WebhookDeliveryElement> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
 
-#[catch(422)] 
-fn unprocessable(_req: &Request) {}
+    if auth.verify(&user.pass) {
+        let webhook = user_webhook(&user, webhook, &mut db)?;
+        let body = webhooks::payload(0, webhooks::PING_EVENT, 0, "{}");
+        let id = add_webhook_delivery(webhook.id, 0, webhooks::PING_EVENT, 0, None, None, &mut db)?;
+        retry_webhook_delivery(id, &body, now(), &mut db)?;
 
-#[catch(500)] 
-fn server_error(_req: &Request) {}
+        Ok(Json(WebhookDeliveryElement {
+            event: 0,
+            kind: webhooks::PING_EVENT.to_string(),
+            time: now(),
+            attempt: 0,
+            status: None,
+            error: None
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations")]
+fn user_stations_get(login: String, db: State<DbConn>, auth: BasicAuth) -> ApiResp<UserStationsResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+
+    if auth.verify(&user.pass) {
+        let stations = get_stations(&login, &mut db)?;
+        Ok(Json(UserStationsResp {
+            stations: stations.into_iter().map(|s| s.id).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[post("/v1/users/<login>/stations", data = "<req>")]
+fn user_stations_post(login: String, req: Validated<UserStationsReq>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+
+    if auth.verify(&user.pass) {
+        let mut station = get_station(req.id, &mut db)?;
+        if station.owner.is_none() {
+            station.owner = Some(user.login);
+            update_station(station, &mut db)?;
+        }
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>")]
+fn user_station_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<StationResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let meta = get_station_meta(station.id, &mut db)?;
+        Ok(Json(station_resp(station, meta)))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[put("/v1/users/<login>/stations/<id>", data = "<req>")]
+fn user_station_put(login: String, id: usize, req: Validated<StationReq>, db: State<DbConn>, ws_reqs: State<WsRequests>, events: State<Events>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let mut station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        if let Some(name) = req.name.clone() {
+            station.name = name;
+        }
+        if let Some(capacity) = req.tank_capacity {
+            station.tank_capacity = Some(capacity);
+        }
+        if let Some(plant) = req.plant.as_ref() {
+            station.plant = station_plant(plant, &mut db, &unprocessable)?;
+        }
+        let mut meta = get_station_meta(station.id, &mut db)?;
+        change_meta(&mut meta, &req);
+        let conf = requested_conf(&req);
+        if let Some(conf) = conf.as_ref() {
+            check_conf(&station, conf, &unprocessable)?;
+        }
+
+        // Nothing is written unless all of the changes are, and the station
+        // only hears about a configuration that was stored.
+        let mut tx = db.start_transaction(TxOpts::default()).or(Err(Status::InternalServerError))?;
+        update_station_meta(&meta, &mut tx)?;
+        let revision = match conf {
+            Some(conf) => Some(record_conf(&mut station, conf, Actor::User, Some(&login), "PUT /v1/users/<login>/stations/<id>", &mut tx)?),
+            None => None
+        };
+        let (id, conf) = (station.id, station.conf.clone());
+        update_station(station, &mut tx)?;
+        tx.commit().or(Err(Status::InternalServerError))?;
+
+        if let Some(revision) = revision {
+            ws_reqs.lock().or(Err(Status::InternalServerError))?.publish(WsRequest::UpdateConf(WsUpdateConf {
+                id,
+                conf: conf.clone().unwrap_or(json!({})),
+                revision
+            }))?;
+            push_event(&events, id, EventKind::Conf, json!({ "conf": conf, "revision": revision }).to_string())?;
+        }
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[patch("/v1/users/<login>/stations/<id>/conf", data = "<req>")]
+fn user_conf_patch(login: String, id: usize, req: Json<Value>, db: State<DbConn>, ws_reqs: State<WsRequests>, events: State<Events>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let mut station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let mut conf = station.conf.clone().unwrap_or(json!({}));
+        merge_patch(&mut conf, &req);
+        let revision = change_conf(&mut station, conf, Actor::User, Some(&login), "PATCH /v1/users/<login>/stations/<id>/conf", &mut db, &unprocessable)?;
+        publish_conf(&ws_reqs, &station, revision)?;
+        save_station(station, Some(revision), &mut db, &events)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/conf/revisions")]
+fn user_conf_revisions_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<ConfRevisionsResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        Ok(Json(ConfRevisionsResp {
+            revisions: get_conf_revisions(station.id, &mut db)?.into_iter().map(|r| ConfRevisionElement {
+                revision: r.revision,
+                time: r.time,
+                actor: r.actor,
+                login: r.login
+            }).collect(),
+            acknowledged: station.conf_acked
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/conf/revisions/<revision>")]
+fn user_conf_revision_get(login: String, id: usize, revision: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<ConfRevisionResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let r = get_conf_revision(station.id, revision, &mut db)?;
+        Ok(Json(ConfRevisionResp {
+            revision: r.revision,
+            time: r.time,
+            actor: r.actor,
+            login: r.login,
+            conf: r.conf
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/conf/diff?<from>&<to>")]
+fn user_conf_diff_get(login: String, id: usize, from: u64, to: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<ConfDiffResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let old = get_conf_revision(station.id, from, &mut db)?;
+        let new = get_conf_revision(station.id, to, &mut db)?;
+        Ok(Json(ConfDiffResp {
+            from,
+            to,
+            changes: diff(&old.conf, &new.conf)
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+// Rolling back stores the old configuration as a new revision, so the rollback
+// itself can be undone.
+#[post("/v1/users/<login>/stations/<id>/conf/rollback", data = "<req>")]
+fn user_conf_rollback_post(login: String, id: usize, req: Validated<RollbackReq>, db: State<DbConn>, ws_reqs: State<WsRequests>, events: State<Events>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let mut station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let conf = get_conf_revision(station.id, req.revision, &mut db)?.conf;
+        let revision = change_conf(&mut station, conf, Actor::User, Some(&login), "POST /v1/users/<login>/stations/<id>/conf/rollback", &mut db, &unprocessable)?;
+        publish_conf(&ws_reqs, &station, revision)?;
+        save_station(station, Some(revision), &mut db, &events)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[delete("/v1/users/<login>/stations/<id>")]
+fn user_station_delete(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let mut station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        station.owner = None;
+        remove_garden_station(station.id, &mut db)?;
+        delete_station_meta(station.id, &mut db)?;
+        update_station(station, &mut db)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/data?<count>")]
+fn user_data_get(login: String, id: usize, count: Option<usize>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<DataResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let data = if let Some(count) = count {
+            get_data_count(station.id, count, &mut db)?
+        } else {
+            get_data(station.id, &mut db)?
+        };
+
+        let units = Units::new(get_unit_prefs(&login, &mut db)?.as_ref(), station.tank_capacity);
+        Ok(Json(DataResp {
+            units: UnitsResp {
+                temperature: units.temperature,
+                tank_fill: units.tank_fill
+            },
+            data: data.iter().map(|d| units.element(d)).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+// Days start at midnight in the station's timezone, UTC if it has none.
+#[get("/v1/users/<login>/stations/<id>/data/daily?<days>")]
+fn user_daily_get(login: String, id: usize, days: Option<usize>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<DailyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let timezone = get_station_meta(station.id, &mut db)?.timezone.unwrap_or(DEFAULT_TIMEZONE.to_string());
+        let tz: Tz = timezone.parse().unwrap_or(Tz::UTC);
+        let days = days.unwrap_or(DAILY_DAYS).clamp(1, DAILY_DAYS_MAX);
+
+        // Some timezones skip midnight on DST days, the first day then starts an hour later.
+        let first = chrono::Utc::now().with_timezone(&tz).date().naive_local() - chrono::Duration::days(days as i64 - 1);
+        let midnight = first.and_hms(0, 0, 0);
+        let since = tz.from_local_datetime(&midnight).earliest()
+            .or_else(|| tz.from_local_datetime(&(midnight + chrono::Duration::hours(1))).earliest())
+            .map(|t| t.timestamp())
+            .unwrap_or(midnight.timestamp()).max(0) as usize;
+        let readings = get_data_since(station.id, since, &mut db)?;
+
+        let units = Units::new(get_unit_prefs(&login, &mut db)?.as_ref(), station.tank_capacity);
+        let convert = |s: Option<analytics::Stats>, f: &dyn Fn(f32) -> f32| s.map(|s| StatsElement {
+            min: f(s.min),
+            max: f(s.max),
+            mean: f(s.mean)
+        });
+
+        Ok(Json(DailyResp {
+            timezone,
+            units: UnitsResp {
+                temperature: units.temperature,
+                tank_fill: units.tank_fill
+            },
+            days: analytics::daily(&readings, tz).into_iter().map(|d| DailyElement {
+                date: d.date.format("%Y-%m-%d").to_string(),
+                count: d.count,
+                moisture: convert(d.moisture, &|v| v),
+                temperature: convert(d.temperature, &|v| units.temperature(v)),
+                humidity: convert(d.humidity, &|v| v),
+                tank_fill: convert(d.tank_fill, &|v| units.tank_fill(v))
+            }).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/state")]
+fn user_state_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<StateResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        Ok(Json(StateResp {
+            state: station.state
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[put("/v1/users/<login>/stations/<id>/state", data = "<req>")]
+fn user_state_put(login: String, id: usize, req: Validated<StateReq>, db: State<DbConn>, ws_reqs: State<WsRequests>, events: State<Events>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        change_state(station, req.state, &login, "PUT /v1/users/<login>/stations/<id>/state", &mut db, &ws_reqs, &events, &unprocessable)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/control")]
+fn user_control_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<ControlResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let policy = get_control_policy(station.id, &mut db)?.ok_or(Status::NotFound)?;
+        Ok(Json(ControlResp {
+            enabled: policy.enabled,
+            dry_run: policy.dry_run,
+            moisture_low: policy.moisture_low,
+            moisture_high: policy.moisture_high,
+            max_duration: policy.max_duration,
+            min_interval: policy.min_interval,
+            tank_min: policy.tank_min,
+            last_start: policy.last_start,
+            active: policy.active,
+            simulated: policy.simulated
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[put("/v1/users/<login>/stations/<id>/control", data = "<req>")]
+fn user_control_put(login: String, id: usize, req: Validated<ControlReq>, db: State<DbConn>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        // Missing bounds come from the plant profile.
+        let range = plant_range(&station, Metric::Moisture, &mut db)?;
+        let moisture_low = req.moisture_low.or(range.map(|r| r.0)).ok_or_else(|| unprocessable.field("moisture_low", "is required without a plant profile"))?;
+        let moisture_high = req.moisture_high.or(range.map(|r| r.1)).ok_or_else(|| unprocessable.field("moisture_high", "is required without a plant profile"))?;
+        if moisture_low >= moisture_high {
+            return Err(unprocessable.field("moisture_low", "must be below moisture_high"));
+        }
+
+        let old = get_control_policy(station.id, &mut db)?;
+        update_control_policy(&ControlPolicyRow {
+            station: station.id,
+            enabled: req.enabled.unwrap_or(true),
+            dry_run: req.dry_run.unwrap_or(false),
+            moisture_low,
+            moisture_high,
+            max_duration: req.max_duration,
+            min_interval: req.min_interval,
+            tank_min: req.tank_min,
+            last_start: old.as_ref().and_then(|p| p.last_start),
+            active: old.as_ref().map(|p| p.active).unwrap_or(false),
+            simulated: old.map(|p| p.simulated).unwrap_or(false)
+        }, &mut db)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[delete("/v1/users/<login>/stations/<id>/control")]
+fn user_control_delete(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        delete_control_policy(station.id, &mut db)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+// Without a threshold, rules alert when the metric leaves the range of the
+// station's plant profile.
+fn rule_threshold(station: &StationRow, req: &AlertRuleReq, db: &mut PooledConn, unprocessable: &Unprocessable) -> Result<f32, Status> {
+    if let Some(threshold) = req.threshold {
+        return Ok(threshold);
+    }
+
+    let (min, max) = plant_range(station, req.metric, db)?.ok_or_else(|| unprocessable.field("threshold", "is required without a plant profile range for this metric"))?;
+    Ok(match req.comparison {
+        Comparison::Below => min,
+        Comparison::Above => max
+    })
+}
+
+fn station_alert_rule(station: &StationRow, rule: u64, db: &mut PooledConn) -> Result<AlertRuleRow, Status> {
+    let rule = get_alert_rule(rule, db)?;
+    if rule.station == station.id {
+        Ok(rule)
+    } else {
+        Err(Status::NotFound)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/alerts/rules")]
+fn user_alert_rules_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<AlertRulesResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        Ok(Json(AlertRulesResp {
+            rules: get_alert_rules(station.id, &mut db)?.into_iter().map(|r| AlertRuleElement {
+                id: r.id,
+                metric: r.metric,
+                comparison: r.comparison,
+                threshold: r.threshold,
+                duration: r.duration,
+                cooldown: r.cooldown,
+                firing: r.firing
+            }).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[post("/v1/users/<login>/stations/<id>/alerts/rules", data = "<req>")]
+fn user_alert_rules_post(login: String, id: usize, req: Validated<AlertRuleReq>, db: State<DbConn>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<AlertRuleCreatedResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let threshold = rule_threshold(&station, &req, &mut db, &unprocessable)?;
+        Ok(Json(AlertRuleCreatedResp {
+            id: add_alert_rule(&AlertRuleRow {
+                id: 0,
+                station: station.id,
+                metric: req.metric,
+                comparison: req.comparison,
+                threshold,
+                duration: req.duration.unwrap_or(0),
+                cooldown: req.cooldown.unwrap_or(0),
+                since: None,
+                firing: false,
+                last_fired: None
+            }, &mut db)?
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+// Changing a rule starts its evaluation over, an alert it was firing is
+// resolved.
+#[put("/v1/users/<login>/stations/<id>/alerts/rules/<rule>", data = "<req>")]
+fn user_alert_rule_put(login: String, id: usize, rule: u64, req: Validated<AlertRuleReq>, db: State<DbConn>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let mut rule = station_alert_rule(&station, rule, &mut db)?;
+        let threshold = rule_threshold(&station, &req, &mut db, &unprocessable)?;
+        if rule.firing {
+            resolve_alerts(rule.id, now(), &mut db)?;
+        }
+
+        rule.metric = req.metric;
+        rule.comparison = req.comparison;
+        rule.threshold = threshold;
+        rule.duration = req.duration.unwrap_or(0);
+        rule.cooldown = req.cooldown.unwrap_or(0);
+        rule.since = None;
+        rule.firing = false;
+        update_alert_rule(&rule, &mut db)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[delete("/v1/users/<login>/stations/<id>/alerts/rules/<rule>")]
+fn user_alert_rule_delete(login: String, id: usize, rule: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let rule = station_alert_rule(&station, rule, &mut db)?;
+        delete_alert_rule(rule.id, &mut db)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/alerts?<resolved>")]
+fn user_alerts_get(login: String, id: usize, resolved: Option<bool>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<AlertsResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        Ok(Json(AlertsResp {
+            alerts: get_alerts(station.id, resolved.unwrap_or(false), ALERTS_MAX, &mut db)?.into_iter().map(|a| AlertElement {
+                id: a.id,
+                rule: a.rule,
+                value: a.value,
+                fired: a.fired,
+                resolved: a.resolved
+            }).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+// Schedules are addressed through their station, so a schedule id belonging to
+// another station is treated as missing.
+fn station_schedule(station: &StationRow, schedule: u64, db: &mut PooledConn) -> Result<ScheduleRow, Status> {
+    let schedule = get_schedule(schedule, db)?;
+    if schedule.station == station.id {
+        Ok(schedule)
+    } else {
+        Err(Status::NotFound)
+    }
+}
+
+fn schedule_element(s: ScheduleRow) -> ScheduleElement {
+    ScheduleElement {
+        id: s.id,
+        times: s.times,
+        days: s.days,
+        duration: s.duration,
+        timezone: Some(s.timezone).filter(|t| !t.is_empty()),
+        enabled: s.enabled
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/schedules")]
+fn user_schedules_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<SchedulesResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        Ok(Json(SchedulesResp {
+            schedules: get_schedules(station.id, &mut db)?.into_iter().map(schedule_element).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[post("/v1/users/<login>/stations/<id>/schedules", data = "<req>")]
+fn user_schedules_post(login: String, id: usize, req: Validated<ScheduleReq>, db: State<DbConn>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<ScheduleCreatedResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let schedule = ScheduleRow {
+            id: 0,
+            station: station.id,
+            times: req.times.clone(),
+            days: req.days.clone().unwrap_or_default(),
+            duration: req.duration,
+            timezone: req.timezone.clone().unwrap_or_default(),
+            enabled: req.enabled.unwrap_or(true)
+        };
+        Plan::from_row(&schedule, get_station_meta(station.id, &mut db)?.timezone.as_deref()).map_err(|e| unprocessable.fields(vec![e]))?;
+
+        Ok(Json(ScheduleCreatedResp {
+            id: add_schedule(&schedule, &mut db)?
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/schedules/<schedule>")]
+fn user_schedule_get(login: String, id: usize, schedule: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<ScheduleElement> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        Ok(Json(schedule_element(station_schedule(&station, schedule, &mut db)?)))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[put("/v1/users/<login>/stations/<id>/schedules/<schedule>", data = "<req>")]
+fn user_schedule_put(login: String, id: usize, schedule: u64, req: Validated<ScheduleReq>, db: State<DbConn>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let mut schedule = station_schedule(&station, schedule, &mut db)?;
+        schedule.times = req.times.clone();
+        schedule.days = req.days.clone().unwrap_or_default();
+        schedule.duration = req.duration;
+        if let Some(timezone) = req.timezone.clone() {
+            schedule.timezone = timezone;
+        }
+        if let Some(enabled) = req.enabled {
+            schedule.enabled = enabled;
+        }
+        Plan::from_row(&schedule, get_station_meta(station.id, &mut db)?.timezone.as_deref()).map_err(|e| unprocessable.fields(vec![e]))?;
+
+        update_schedule(&schedule, &mut db)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[delete("/v1/users/<login>/stations/<id>/schedules/<schedule>")]
+fn user_schedule_delete(login: String, id: usize, schedule: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let schedule = station_schedule(&station, schedule, &mut db)?;
+        delete_schedule(schedule.id, &mut db)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/schedules/<schedule>/preview?<count>")]
+fn user_schedule_preview_get(login: String, id: usize, schedule: u64, count: Option<usize>, db: State<DbConn>, unprocessable: Unprocessable, auth: BasicAuth) -> ApiResp<SchedulePreviewResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let station_tz = get_station_meta(station.id, &mut db)?.timezone;
+        let plan = Plan::from_row(&station_schedule(&station, schedule, &mut db)?, station_tz.as_deref()).map_err(|e| unprocessable.fields(vec![e]))?;
+        let count = count.unwrap_or(PREVIEW_COUNT).min(PREVIEW_COUNT_MAX);
+
+        Ok(Json(SchedulePreviewResp {
+            runs: plan.next_runs(chrono::Utc::now(), count).into_iter().map(|(start, end)| SchedulePreviewElement {
+                start: start.timestamp() as usize,
+                end: end.timestamp() as usize
+            }).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/schedules/<schedule>/runs")]
+fn user_schedule_runs_get(login: String, id: usize, schedule: u64, db: State<DbConn>, auth: BasicAuth) -> ApiResp<ScheduleRunsResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let schedule = station_schedule(&station, schedule, &mut db)?;
+        Ok(Json(ScheduleRunsResp {
+            runs: get_schedule_runs(schedule.id, SCHEDULE_RUNS, &mut db)?.into_iter().map(|r| ScheduleRunElement {
+                time: r.time,
+                outcome: r.outcome
+            }).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/history?<before>&<count>")]
+fn user_history_get(login: String, id: usize, before: Option<u64>, count: Option<usize>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<HistoryResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let count = count.unwrap_or(HISTORY_PAGE).min(HISTORY_PAGE_MAX);
+        let history = get_history(station.id, before, count, &mut db)?;
+        let next = if history.len() == count { history.last().map(|h| h.id) } else { None };
+
+        Ok(Json(HistoryResp {
+            history: history.into_iter().map(|h| HistoryElement {
+                id: h.id,
+                time: h.time,
+                actor: h.actor,
+                login: h.login,
+                kind: h.kind,
+                old_value: h.old_value,
+                new_value: h.new_value,
+                source: h.source
+            }).collect(),
+            next
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+// Only the sensors of a station can be calibrated.
+fn sensor_metric(metric: &str) -> Result<Metric, Status> {
+    metric.parse().ok().filter(|m| anomalies::SENSORS.contains(m)).ok_or(Status::NotFound)
+}
+
+#[get("/v1/users/<login>/stations/<id>/calibrations")]
+fn user_calibrations_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<CalibrationsResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        Ok(Json(CalibrationsResp {
+            calibrations: get_calibrations(station.id, &mut db)?.into_iter().map(|c| CalibrationElement {
+                metric: c.metric,
+                points: c.points
+            }).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+// Points map raw sensor values to calibrated ones. Two points make a linear
+// mapping, more make a piecewise linear one. Only readings that arrive after
+// the change are affected.
+#[put("/v1/users/<login>/stations/<id>/calibrations/<metric>", data = "<req>")]
+fn user_calibration_put(login: String, id: usize, metric: String, req: Validated<CalibrationReq>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let mut points = req.points.clone();
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        update_calibration(&CalibrationRow {
+            station: station.id,
+            metric: sensor_metric(&metric)?,
+            points
+        }, &mut db)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[delete("/v1/users/<login>/stations/<id>/calibrations/<metric>")]
+fn user_calibration_delete(login: String, id: usize, metric: String, db: State<DbConn>, auth: BasicAuth) -> ApiResp<EmptyResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        delete_calibration(station.id, sensor_metric(&metric)?, &mut db)?;
+        Ok(Json(EmptyResp {}))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+// A sensor's status is the fault of its latest reading, "ok" if there was none
+// and "no_data" if it hasn't reported within the window.
+#[get("/v1/users/<login>/stations/<id>/health")]
+fn user_health_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<HealthResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let since = now().saturating_sub(HEALTH_WINDOW);
+        let readings = get_data_since(station.id, since, &mut db)?;
+        let faults = get_data_faults_since(station.id, since, &mut db)?;
+
+        let sensors: Vec<SensorHealthElement> = anomalies::SENSORS.iter().map(|metric| {
+            let latest = readings.iter().rev().find_map(|d| metric.value(d).map(|v| (d.time, v)));
+            let metric_faults: Vec<&DataFaultRow> = faults.iter().filter(|f| f.metric == *metric).collect();
+            let status = match latest {
+                Some((time, _)) => metric_faults.iter().rev().find(|f| f.time == time).map(|f| f.fault.as_str()).unwrap_or("ok"),
+                None => "no_data"
+            };
+            SensorHealthElement {
+                metric: *metric,
+                status: status.to_string(),
+                value: latest.map(|(_, v)| v),
+                last_reading: latest.map(|(t, _)| t),
+                faults: metric_faults.len(),
+                last_fault: metric_faults.last().map(|f| f.time)
+            }
+        }).collect();
+
+        Ok(Json(HealthResp {
+            healthy: sensors.iter().all(|s| s.status == "ok" || s.status == "no_data"),
+            sensors
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/faults")]
+fn user_faults_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<DataFaultsResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        Ok(Json(DataFaultsResp {
+            faults: get_data_faults(station.id, FAULTS_MAX, &mut db)?.into_iter().map(|f| DataFaultElement {
+                time: f.time,
+                metric: f.metric,
+                fault: f.fault,
+                value: f.value
+            }).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/tank")]
+fn user_tank_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<TankResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let prediction = analytics::predict_station(station.id, &mut db)?;
+        Ok(Json(TankResp {
+            fill: prediction.fill,
+            rate: prediction.rate,
+            empty_at: prediction.empty_at,
+            last_refill: prediction.last_refill
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/tank/refills?<days>")]
+fn user_refills_get(login: String, id: usize, days: Option<usize>, db: State<DbConn>, auth: BasicAuth) -> ApiResp<RefillsResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let days = days.unwrap_or(REFILL_DAYS).min(REFILL_DAYS_MAX);
+        let since = now().saturating_sub(days * 86400);
+        let mut readings = get_data_since(station.id, since, &mut db)?;
+        anomalies::exclude_faults(&mut readings, &get_data_faults_since(station.id, since, &mut db)?);
+        Ok(Json(RefillsResp {
+            refills: analytics::refills(&readings).into_iter().rev().map(|r| RefillElement {
+                time: r.time,
+                from: r.from,
+                to: r.to
+            }).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[get("/v1/users/<login>/stations/<id>/outages")]
+fn user_outages_get(login: String, id: usize, db: State<DbConn>, auth: BasicAuth) -> ApiResp<OutagesResp> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let status = get_station_status(station.id, &mut db)?;
+        Ok(Json(OutagesResp {
+            last_seen: status.as_ref().map(|s| s.last_seen),
+            offline: status.map(|s| s.offline).unwrap_or(false),
+            outages: get_outages(station.id, OUTAGES_MAX, &mut db)?.into_iter().map(|o| OutageElement {
+                started: o.started,
+                ended: o.ended
+            }).collect()
+        }))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+// Readings in events are always in °C and percent whatever the user prefers,
+// as stated by their `units` field.
+#[get("/v1/users/<login>/stations/<id>/events")]
+fn user_events_get(login: String, id: usize, db: State<DbConn>, events: State<Events>, last_event_id: LastEventId, auth: BasicAuth) -> Result<Content<Stream<EventStream>>, Status> {
+    let mut db = db.lock().or(Err(Status::InternalServerError))?.get_conn().or(Err(Status::InternalServerError))?;
+    let user = get_user(&login, &mut db)?;
+    let station = get_station(id, &mut db)?;
+
+    if station.owner == Some(user.login) && auth.verify(&user.pass) {
+        let last_id = match last_event_id.0 {
+            Some(last_id) => last_id,
+            None => events.lock().or(Err(Status::InternalServerError))?.last_id()
+        };
+
+        let stream = EventStream::open(events.inner().clone(), station.id, last_id).ok_or(Status::ServiceUnavailable)?;
+        Ok(Content(ContentType::new("text", "event-stream"), Stream::from(stream)))
+    } else {
+        Err(Status::Unauthorized)
+    }
+}
+
+#[catch(400)] 
+fn bad_request(_req: &Request) {}
+
+#[catch(401)] 
+fn unauthorised(_req: &Request) {}
+
+#[catch(404)] 
+fn not_found(_req: &Request) {}
+
+#[catch(409)] 
+fn conflict(_req: &Request) {}
+
+#[catch(422)] 
+fn unprocessable(req: &Request) -> Json<ErrorsResp> {
+    Json(ErrorsResp {
+        errors: req.local_cache(FieldErrors::default).0.clone()
+    })
+}
+
+#[catch(500)] 
+fn server_error(_req: &Request) {}
+
+#[catch(503)] 
+fn unavailable(_req: &Request) {}
+
+pub fn run(db_conn: DbConn, conf: Conf, ws_reqs: WsRequests, events: Events) {
+    let mut config = RocketConfig::read().or_else(|_| RocketConfig::active_default()).unwrap().active().clone();
+    if ListenMode::from_conf(&conf).http() {
+        // The mux forwards plain HTTP, it terminates TLS itself.
+        if config.tls_enabled() {
+            panic!("Rocket's TLS can't be used when the WebSocket endpoint is served on the HTTP port, set ws_tls_cert and ws_tls_key instead");
+        }
+        config.set_address("127.0.0.1").unwrap();
+        config.set_port(http_mux::internal_port(&conf));
+    }
+    // Half of the workers are kept free for regular requests by default.
+    let streams = conf.get("event_streams").and_then(|n| n.parse().ok()).unwrap_or(config.workers as usize / 2);
+    events.lock().unwrap().limit_streams(streams);
 
-pub fn run(db_conn: DbConn, conf: Conf, ws_reqs: WsRequests) {
-    rocket::ignite()
-        .mount("/", routes![index, options, root, stations_post, station_get, station_put, data_post, state_get, state_put, users_post, user_get, user_put, user_delete, user_stations_get, user_stations_post, user_station_get, user_station_put, user_station_delete, user_data_get, user_state_get, user_state_put])
-        .register(catchers![bad_request, unauthorised, not_found, conflict, unprocessable, server_error])
-        .manage(db_conn).manage(conf).manage(ws_reqs).launch();
+    rocket::custom(config)
+        .mount("/", routes![index, options, root, plants_get, plant_get, stations_post, station_get, station_put, data_post, state_get, state_put, users_post, user_get, user_put, user_delete, user_stations_get, user_stations_post, user_station_get, user_station_put, user_conf_patch, user_conf_revisions_get, user_conf_revision_get, user_conf_diff_get, user_conf_rollback_post, user_station_delete, user_data_get, user_state_get, user_state_put, user_history_get, user_events_get, user_schedules_get, user_schedules_post, user_schedule_get, user_schedule_put, user_schedule_delete, user_schedule_preview_get, user_schedule_runs_get, user_control_get, user_control_put, user_control_delete, user_alert_rules_get, user_alert_rules_post, user_alert_rule_put, user_alert_rule_delete, user_alerts_get, user_webhooks_get, user_webhooks_post, user_webhook_get, user_webhook_put, user_webhook_delete, user_webhook_deliveries_get, user_webhook_test_post, user_notifications_get, user_notifications_put, user_outages_get, user_tank_get, user_refills_get, user_health_get, user_faults_get, user_calibrations_get, user_calibration_put, user_calibration_delete, user_units_get, user_units_put, user_gardens_get, user_gardens_post, user_garden_get, user_garden_put, user_garden_delete, user_garden_data_get, user_garden_state_put, user_garden_conf_put, user_daily_get])
+        .register(catchers![bad_request, unauthorised, not_found, conflict, unprocessable, server_error, unavailable])
+        .manage(db_conn).manage(conf).manage(ws_reqs).manage(events).launch();
 }
@@ -0,0 +1,131 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use mysql::Pool;
+use rocket::http::Status;
+use uuid::Uuid;
+
+use crate::model::*;
+use crate::ws_notifier::WsRequest;
+
+const MESSAGE_TTL: Duration = Duration::new(300, 0);
+const PRESENCE_TTL: Duration = Duration::new(60, 0);
+
+// Carries requests from the HTTP API to whichever notifier holds the
+// station's connection.
+pub trait Bus: Send {
+    fn publish(&mut self, req: WsRequest) -> Result<(), Status>;
+    fn poll(&mut self) -> Result<Vec<WsRequest>, Status>;
+    fn connected(&mut self, station: usize) -> Result<(), Status>;
+    fn disconnected(&mut self, station: usize) -> Result<(), Status>;
+}
+
+pub fn from_conf(conf: &HashMap<String, String>, db: &Pool) -> Box<dyn Bus> {
+    match conf.get("bus").map(String::as_str) {
+        Some("db") => {
+            let instance = conf.get("instance_id").cloned().unwrap_or_else(|| Uuid::new_v4().to_simple().to_string());
+            println!("[BUS]: Using database bus as instance {}", instance);
+            // Stations held by this instance before a restart aren't connected anymore.
+            let cleared = match db.get_conn() {
+                Ok(mut conn) => delete_instance_presence(&instance, &mut conn),
+                Err(_) => Err(Status::InternalServerError)
+            };
+            if cleared.is_err() {
+                println!("[BUS]: Failed to clear the stations of instance {}", instance);
+            }
+            Box::new(DbBus {
+                db: db.clone(),
+                instance
+            })
+        }
+        _ => Box::new(LocalBus {
+            reqs: Vec::new()
+        })
+    }
+}
+
+// Single instance deployments: requests are simply queued in memory.
+pub struct LocalBus {
+    reqs: Vec<WsRequest>
+}
+
+impl Bus for LocalBus {
+    fn publish(&mut self, req: WsRequest) -> Result<(), Status> {
+        self.reqs.push(req);
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<WsRequest>, Status> {
+        Ok(std::mem::replace(&mut self.reqs, Vec::new()))
+    }
+
+    fn connected(&mut self, _station: usize) -> Result<(), Status> {
+        Ok(())
+    }
+
+    fn disconnected(&mut self, _station: usize) -> Result<(), Status> {
+        Ok(())
+    }
+}
+
+// Multiple instances sharing a database: every notifier records the stations
+// connected to it, and requests are addressed to the instance holding the
+// station, which polls for them. Instances renew their stations whenever they
+// poll, so those of an instance that stopped polling expire after
+// `PRESENCE_TTL`.
+//
+// Only requests for stations go through the bus. Event streams, webhooks and
+// emails are fed by each instance's own event log, so clients only see the
+// events of the instance they are connected to, and webhooks and digests are
+// sent for the events each instance produced.
+pub struct DbBus {
+    db: Pool,
+    instance: String
+}
+
+impl Bus for DbBus {
+    fn publish(&mut self, req: WsRequest) -> Result<(), Status> {
+        let mut db = self.db.get_conn().or(Err(Status::InternalServerError))?;
+        if let Some(instance) = get_presence(req.station(), &mut db)? {
+            let req = serde_json::to_string(&req).or(Err(Status::InternalServerError))?;
+            add_bus_message(&instance, &req, &mut db)?;
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<WsRequest>, Status> {
+        let mut db = self.db.get_conn().or(Err(Status::InternalServerError))?;
+        touch_presence(&self.instance, &mut db)?;
+        delete_presence_before(PRESENCE_TTL, &mut db)?;
+        delete_bus_messages_before(MESSAGE_TTL, &mut db)?;
+        Ok(take_bus_messages(&self.instance, &mut db)?.into_iter().filter_map(|r| serde_json::from_str(&r).ok()).collect())
+    }
+
+    fn connected(&mut self, station: usize) -> Result<(), Status> {
+        let mut db = self.db.get_conn().or(Err(Status::InternalServerError))?;
+        set_presence(station, &self.instance, &mut db)
+    }
+
+    fn disconnected(&mut self, station: usize) -> Result<(), Status> {
+        let mut db = self.db.get_conn().or(Err(Status::InternalServerError))?;
+        delete_presence(station, &self.instance, &mut db)
+    }
+}
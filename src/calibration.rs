@@ -0,0 +1,81 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use mysql::PooledConn;
+use rocket::http::Status;
+
+use crate::model::*;
+use crate::alerts::Metric;
+
+pub const POINTS_MAX: usize = 32;
+
+// Maps a raw value through the line segments between the calibration points,
+// which are sorted by their raw value. Values outside of the calibrated range
+// follow the outermost segments.
+pub fn apply(points: &[(f32, f32)], raw: f32) -> f32 {
+    if points.len() < 2 {
+        return raw;
+    }
+
+    let i = (0..points.len() - 2).find(|i| raw <= points[i + 1].0).unwrap_or(points.len() - 2);
+    let ((x0, y0), (x1, y1)) = (points[i], points[i + 1]);
+    y0 + (raw - x0) * (y1 - y0) / (x1 - x0)
+}
+
+// Readings are stored calibrated, later changes to the calibration don't
+// affect readings that were already stored.
+pub fn calibrate(station: usize, raw: &DataReq, db: &mut PooledConn) -> Result<DataReq, Status> {
+    let calibrations = get_calibrations(station, db)?;
+    let value = |metric: Metric, raw: Option<f32>| match calibrations.iter().find(|c| c.metric == metric) {
+        Some(c) => raw.map(|r| apply(&c.points, r)),
+        None => raw
+    };
+
+    Ok(DataReq {
+        moisture: value(Metric::Moisture, raw.moisture),
+        temperature: value(Metric::Temperature, raw.temperature),
+        humidity: value(Metric::Humidity, raw.humidity),
+        tank_fill: value(Metric::TankFill, raw.tank_fill)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POINTS: &[(f32, f32)] = &[(0.0, 0.0), (10.0, 100.0), (20.0, 120.0)];
+
+    #[test]
+    fn passes_values_through_without_a_segment() {
+        assert_eq!(apply(&[], 42.0), 42.0);
+        assert_eq!(apply(&[(1.0, 2.0)], 42.0), 42.0);
+    }
+
+    #[test]
+    fn selects_the_segment_containing_the_value() {
+        assert_eq!(apply(POINTS, 5.0), 50.0);
+        assert_eq!(apply(POINTS, 10.0), 100.0);
+        assert_eq!(apply(POINTS, 15.0), 110.0);
+    }
+
+    #[test]
+    fn extrapolates_along_the_outermost_segments() {
+        assert_eq!(apply(POINTS, -5.0), -50.0);
+        assert_eq!(apply(POINTS, 30.0), 140.0);
+    }
+}
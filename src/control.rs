@@ -0,0 +1,176 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::sync::{Mutex, Arc};
+use std::time::Duration;
+use std::thread;
+
+use mysql::{Pool, PooledConn};
+use rocket::http::Status;
+use serde_json::json;
+
+use crate::model::*;
+use crate::bus::Bus;
+use crate::events::*;
+use crate::state::{Actor, StationState};
+use crate::ws_notifier::*;
+
+const TICK: Duration = Duration::new(10, 0);
+const SOURCE: &str = "control";
+
+// Changes a station's state on behalf of the server and tells the station.
+pub fn set_state(mut station: StationRow, state: StationState, source: &str, db: &mut PooledConn, reqs: &Arc<Mutex<Box<dyn Bus>>>, events: &Arc<Mutex<EventLog>>) -> Result<(), Status> {
+    add_history(station.id, Actor::System, None, "state", Some(station.state.as_str()), Some(state.as_str()), source, db)?;
+
+    let id = station.id;
+    station.state = state;
+    station.unknown_state = None;
+    update_station(station, db)?;
+
+    reqs.lock().or(Err(Status::InternalServerError))?.publish(WsRequest::UpdateState(WsUpdateState {
+        id,
+        state
+    }))?;
+    events.lock().or(Err(Status::InternalServerError))?.push(id, EventKind::State, json!({ "state": state }).to_string());
+    Ok(())
+}
+
+// Waters when the moisture drops below `moisture_low` and stops once it
+// reaches `moisture_high`, the tank runs low or `max_duration` has passed.
+// Only watering started by the policy itself is stopped by it. A dry run
+// leaves the station alone and follows its own simulated watering instead.
+pub fn evaluate(reading: &DataRow, db: &mut PooledConn, reqs: &Arc<Mutex<Box<dyn Bus>>>, events: &Arc<Mutex<EventLog>>) -> Result<(), Status> {
+    let mut policy = match get_control_policy(reading.station, db)? {
+        Some(policy) if policy.enabled => policy,
+        _ => return Ok(())
+    };
+    let moisture = match reading.moisture {
+        Some(moisture) => moisture,
+        None => return Ok(())
+    };
+    let tank_low = match (policy.tank_min, reading.tank_fill) {
+        (Some(min), Some(fill)) => fill < min,
+        _ => false
+    };
+
+    let station = get_station(reading.station, db)?;
+    let time = reading.time;
+
+    let state = match policy.dry_run {
+        true if policy.simulated => StationState::Watering,
+        true => StationState::Idle,
+        false => station.state
+    };
+    let started = if policy.dry_run { policy.simulated } else { policy.active };
+
+    let (target, reason) = match state {
+        StationState::Idle if moisture < policy.moisture_low => {
+            if tank_low {
+                println!("[CONTROL]: Station {} is dry but its tank is low", station.id);
+                return Ok(());
+            }
+            if policy.last_start.map(|t| time < t + policy.min_interval as usize).unwrap_or(false) {
+                return Ok(());
+            }
+            (StationState::Watering, "moisture low")
+        }
+        StationState::Watering if started => {
+            if moisture >= policy.moisture_high {
+                (StationState::Idle, "moisture reached")
+            } else if tank_low {
+                (StationState::Idle, "tank low")
+            } else if policy.last_start.map(|t| time >= t + policy.max_duration as usize).unwrap_or(false) {
+                (StationState::Idle, "max duration")
+            } else {
+                return Ok(());
+            }
+        }
+        _ => return Ok(())
+    };
+
+    apply(&mut policy, station, target, reason, time, db, reqs, events)
+}
+
+// Stops watering that exceeded its maximum duration even if the station
+// stopped sending readings. In deployments with several instances sharing a
+// database all but one should set `control = "off"`.
+pub fn run(db_conn: Arc<Mutex<Pool>>, conf: HashMap<String, String>, reqs: Arc<Mutex<Box<dyn Bus>>>, events: Arc<Mutex<EventLog>>) {
+    if conf.get("control").map(String::as_str) == Some("off") {
+        return;
+    }
+
+    loop {
+        thread::sleep(TICK);
+        let time = now();
+        let mut db = match db_conn.lock().unwrap().get_conn() {
+            Ok(db) => db,
+            Err(_) => {
+                println!("[CONTROL]: Failed to connect to the database");
+                continue;
+            }
+        };
+
+        let policies = match get_active_control_policies(&mut db) {
+            Ok(policies) => policies,
+            Err(_) => {
+                println!("[CONTROL]: Failed to load control policies");
+                continue;
+            }
+        };
+
+        for mut policy in policies.into_iter() {
+            if policy.last_start.map(|t| time < t + policy.max_duration as usize).unwrap_or(true) {
+                continue;
+            }
+            let simulating = policy.dry_run && policy.simulated;
+            match get_station(policy.station, &mut db) {
+                Ok(station) if simulating || (!policy.dry_run && policy.active && station.state == StationState::Watering) => {
+                    apply(&mut policy, station, StationState::Idle, "max duration", time, &mut db, &reqs, &events).unwrap_or_else(|_| println!("[CONTROL]: Failed to stop station {}", policy.station));
+                }
+                // Someone else already stopped the station, or the dry run
+                // was switched off or on in the meantime.
+                Ok(_) => {
+                    policy.active = false;
+                    policy.simulated = false;
+                    update_control_policy(&policy, &mut db).ok();
+                }
+                Err(_) => ()
+            }
+        }
+    }
+}
+
+fn apply(policy: &mut ControlPolicyRow, station: StationRow, target: StationState, reason: &str, time: usize, db: &mut PooledConn, reqs: &Arc<Mutex<Box<dyn Bus>>>, events: &Arc<Mutex<EventLog>>) -> Result<(), Status> {
+    if target == StationState::Watering {
+        policy.last_start = Some(time);
+    }
+
+    if policy.dry_run {
+        let simulated = if policy.simulated { StationState::Watering } else { StationState::Idle };
+        println!("[CONTROL]: Would set station {} to {} ({}, dry run)", station.id, target, reason);
+        add_history(station.id, Actor::System, None, "dry_run", Some(simulated.as_str()), Some(target.as_str()), &format!("{} ({})", SOURCE, reason), db)?;
+        policy.simulated = target == StationState::Watering;
+    } else if station.state.can_transition(target, Actor::System) {
+        println!("[CONTROL]: Setting station {} to {} ({})", station.id, target, reason);
+        set_state(station, target, &format!("{} ({})", SOURCE, reason), db, reqs, events)?;
+        policy.active = target == StationState::Watering;
+    }
+
+    update_control_policy(policy, db)
+}
@@ -0,0 +1,338 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Email notifications.
+//!
+//! Enabled by setting `smtp_host` in `conf.toml`. The other keys are
+//! `smtp_port` (587), `smtp_starttls` (true), `smtp_user`, `smtp_pass` and
+//! `smtp_from`. With `smtp_starttls = "false"` and no credentials the server
+//! is spoken to in plain text, e.g. a local SMTP sink.
+//!
+//! Messages are rendered from templates in which `{{name}}` is replaced by the
+//! value of the variable `name`. The defaults can be overridden with the keys
+//! `email_subject`, `email_body`, `email_digest_subject` and
+//! `email_digest_body`. Single messages know `name`, `station`,
+//! `station_name`, `summary` and `time`, digests `name`, `count` and `items`.
+//!
+//! Digests are collected in memory, notifications that are waiting for the
+//! next digest are lost when the process stops.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, Arc};
+use std::time::{Duration, Instant};
+use std::thread;
+
+use chrono::{TimeZone, Utc};
+use lettre::{Message, SmtpTransport, Transport};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use mysql::{Pool, PooledConn};
+use serde_json::Value;
+
+use crate::model::*;
+use crate::events::*;
+use crate::units::{Units, TemperatureUnit, TankUnit};
+
+pub const EVENT_TYPES: &[&str] = &["alert", "outage", "recovered", "offline", "online", "state", "fault"];
+pub const DEFAULT_EVENTS: &[&str] = &["alert", "outage", "recovered"];
+pub const DEFAULT_DIGEST_INTERVAL: u64 = 3600;
+
+const TICK: Duration = Duration::new(5, 0);
+const SMTP_PORT: u16 = 587;
+
+const SUBJECT: &str = "[Thyme] {{station_name}} {{summary}}";
+const BODY: &str = "Hello {{name}},\n\n{{station_name}} (station {{station}}) {{summary}} at {{time}}.\n\n-- \nThyme\n";
+const DIGEST_SUBJECT: &str = "[Thyme] {{count}} new notifications";
+const DIGEST_BODY: &str = "Hello {{name}},\n\nhere is what happened since the last summary:\n\n{{items}}\n-- \nThyme\n";
+
+pub struct Mailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+    templates: HashMap<&'static str, String>
+}
+
+struct Digest {
+    started: Instant,
+    interval: Duration,
+    email: String,
+    name: String,
+    items: Vec<String>
+}
+
+impl Mailer {
+    // Invalid settings are logged and disable notifications instead of
+    // stopping the process.
+    pub fn from_conf(conf: &HashMap<String, String>) -> Option<Self> {
+        let host = conf.get("smtp_host")?;
+        let port = conf.get("smtp_port").and_then(|p| p.parse().ok()).unwrap_or(SMTP_PORT);
+
+        // STARTTLS verifies the certificate against the host name, so `smtp_host`
+        // can't be an IP address then.
+        let builder = if conf.get("smtp_starttls").map(String::as_str) == Some("false") {
+            SmtpTransport::builder_dangerous(host)
+        } else {
+            match SmtpTransport::starttls_relay(host) {
+                Ok(builder) => builder,
+                Err(e) => {
+                    println!("[EMAIL]: Invalid smtp_host {} ({}), notifications are disabled", host, e);
+                    return None;
+                }
+            }
+        };
+        let builder = match (conf.get("smtp_user"), conf.get("smtp_pass")) {
+            (Some(user), Some(pass)) => builder.credentials(Credentials::new(user.clone(), pass.clone())),
+            _ => builder
+        };
+
+        let from = match conf.get("smtp_from").map(String::as_str).unwrap_or("stomata@localhost").parse() {
+            Ok(from) => from,
+            Err(e) => {
+                println!("[EMAIL]: Invalid smtp_from ({}), notifications are disabled", e);
+                return None;
+            }
+        };
+
+        let mut templates = HashMap::new();
+        for (key, default) in [("email_subject", SUBJECT), ("email_body", BODY), ("email_digest_subject", DIGEST_SUBJECT), ("email_digest_body", DIGEST_BODY)].iter() {
+            templates.insert(*key, conf.get(*key).cloned().unwrap_or(default.to_string()));
+        }
+
+        println!("[EMAIL]: Sending notifications through {}:{}", host, port);
+        Some(Self {
+            transport: builder.port(port).build(),
+            from,
+            templates
+        })
+    }
+
+    fn send(&self, to: &str, subject: String, body: String) -> Result<(), String> {
+        let msg = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse().map_err(|e| format!("{}", e))?)
+            .subject(subject)
+            .body(body)
+            .map_err(|e| e.to_string())?;
+        self.transport.send(&msg).map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn render(&self, template: &str, vars: &[(&str, String)]) -> String {
+        vars.iter().fold(self.templates[template].clone(), |s, (k, v)| s.replace(&format!("{{{{{}}}}}", k), v))
+    }
+}
+
+pub fn run(db_conn: Arc<Mutex<Pool>>, conf: HashMap<String, String>, events: Arc<Mutex<EventLog>>) {
+    let mailer = match Mailer::from_conf(&conf) {
+        Some(mailer) => mailer,
+        None => return
+    };
+    let mut last_id = events.lock().unwrap().last_id();
+    let mut digests: HashMap<String, Digest> = HashMap::new();
+
+    loop {
+        thread::sleep(TICK);
+        let new = events.lock().unwrap().all_since(last_id);
+
+        // The events are picked up again on the next tick if the database
+        // can't be reached.
+        if !new.is_empty() {
+            let mut db = match db_conn.lock().unwrap().get_conn() {
+                Ok(db) => db,
+                Err(_) => {
+                    println!("[EMAIL]: Failed to connect to the database");
+                    continue;
+                }
+            };
+            for event in new.iter() {
+                notify(&mailer, event, &mut digests, &mut db);
+            }
+        }
+        if let Some(last) = new.last() {
+            last_id = last.id;
+        }
+
+        let due: Vec<String> = digests.iter().filter(|(_, d)| d.started.elapsed() >= d.interval).map(|(login, _)| login.clone()).collect();
+        for login in due.into_iter() {
+            let d = digests.remove(&login).unwrap();
+            let vars = [("name", d.name.clone()), ("count", d.items.len().to_string()), ("items", d.items.iter().map(|i| format!("- {}\n", i)).collect())];
+            if let Err(e) = mailer.send(&d.email, mailer.render("email_digest_subject", &vars), mailer.render("email_digest_body", &vars)) {
+                println!("[EMAIL]: Failed to send digest to {} ({})", login, e);
+            }
+        }
+    }
+}
+
+fn notify(mailer: &Mailer, event: &Event, digests: &mut HashMap<String, Digest>, db: &mut PooledConn) {
+    let station = match get_station(event.station, db) {
+        Ok(station) => station,
+        Err(_) => return
+    };
+    let user = match station.owner.as_ref().map(|o| get_user(o, db)) {
+        Some(Ok(user)) => user,
+        _ => return
+    };
+    let email = match user.email.clone() {
+        Some(email) => email,
+        None => return
+    };
+
+    let (events, digest, interval) = match get_notification_prefs(&user.login, db) {
+        Ok(Some(prefs)) => (prefs.events, prefs.digest, prefs.digest_interval),
+        Ok(None) => (DEFAULT_EVENTS.iter().map(|e| e.to_string()).collect(), false, DEFAULT_DIGEST_INTERVAL),
+        Err(_) => return
+    };
+    if !events.iter().any(|e| e == event.kind.name()) {
+        return;
+    }
+    let units = match get_unit_prefs(&user.login, db) {
+        Ok(prefs) => Units::new(prefs.as_ref(), station.tank_capacity),
+        Err(_) => return
+    };
+    let summary = match summary(event, &units) {
+        Some(summary) => summary,
+        None => return
+    };
+    let time = Utc.timestamp(now() as i64, 0).format("%Y-%m-%d %H:%M UTC").to_string();
+
+    if digest {
+        digests.entry(user.login.clone()).or_insert_with(|| Digest {
+            started: Instant::now(),
+            interval: Duration::from_secs(interval),
+            email,
+            name: user.name.clone(),
+            items: Vec::new()
+        }).items.push(format!("{}: {} {}", time, station.name, summary));
+    } else {
+        let vars = [("name", user.name), ("station", station.id.to_string()), ("station_name", station.name), ("summary", summary), ("time", time)];
+        if let Err(e) = mailer.send(&email, mailer.render("email_subject", &vars), mailer.render("email_body", &vars)) {
+            println!("[EMAIL]: Failed to notify {} ({})", user.login, e);
+        }
+    }
+}
+
+// Events carry readings in °C and percent, the messages use the units the
+// user prefers.
+fn summary(event: &Event, units: &Units) -> Option<String> {
+    let data: Value = serde_json::from_str(&event.data).ok()?;
+    match event.kind {
+        EventKind::Alert => {
+            let kind = data["metric"].as_str()?;
+            let metric = kind.replace('_', " ");
+            let condition = format!("{} {}", data["comparison"].as_str()?, reading(kind, &data["threshold"], units)?);
+            let value = reading(kind, &data["value"], units)?;
+            match data["status"].as_str()? {
+                "firing" => Some(format!("reports {} {} ({})", metric, condition, value)),
+                _ => Some(format!("no longer reports {} {} ({})", metric, condition, value))
+            }
+        }
+        EventKind::State => Some(format!("changed to {}", data["state"].as_str()?)),
+        EventKind::Online => Some("came online".to_string()),
+        EventKind::Offline => Some("went offline".to_string()),
+        EventKind::Outage => {
+            let last_seen = Utc.timestamp(data["last_seen"].as_i64()?, 0).format("%Y-%m-%d %H:%M UTC");
+            Some(format!("has not been heard from since {}", last_seen))
+        }
+        EventKind::Recovered => Some("is back online".to_string()),
+        EventKind::Fault => {
+            let metric = data["metric"].as_str()?.replace('_', " ");
+            let fault = data["fault"].as_str()?.replace('_', " ");
+            match data["control_suppressed"].as_bool()? {
+                true => Some(format!("reports a faulty {} reading ({}), automatic watering is paused", metric, fault)),
+                false => Some(format!("reports a faulty {} reading ({})", metric, fault))
+            }
+        }
+        _ => None
+    }
+}
+
+fn reading(metric: &str, value: &Value, units: &Units) -> Option<String> {
+    let value = value.as_f64()? as f32;
+    Some(match metric {
+        "temperature" if units.temperature == TemperatureUnit::Fahrenheit => format!("{} °F", units.temperature(value)),
+        "temperature" => format!("{} °C", value),
+        "tank_fill" if units.tank_fill == TankUnit::Litres => format!("{} l", units.tank_fill(value)),
+        "moisture" | "humidity" | "tank_fill" => format!("{} %", value),
+        "tank_empty_in" => format!("{} h", value),
+        _ => value.to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mailer(templates: &[(&str, &str)]) -> Mailer {
+        let mut conf: HashMap<String, String> = templates.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        conf.insert("smtp_host".to_string(), "localhost".to_string());
+        conf.insert("smtp_starttls".to_string(), "false".to_string());
+        Mailer::from_conf(&conf).unwrap()
+    }
+
+    fn event(kind: EventKind, data: Value) -> Event {
+        Event {
+            id: 1,
+            station: 1,
+            kind,
+            data: data.to_string()
+        }
+    }
+
+    fn units(temperature: TemperatureUnit, tank_fill: TankUnit) -> Units {
+        Units::new(Some(&UnitPrefsRow {
+            login: "user".to_string(),
+            temperature,
+            tank_fill
+        }), Some(10.0))
+    }
+
+    #[test]
+    fn renders_templates() {
+        let mailer = mailer(&[("email_subject", "{{station_name}}: {{summary}} {{unknown}}")]);
+        let vars = [("station_name", "Basil".to_string()), ("summary", "came online".to_string())];
+        assert_eq!(mailer.render("email_subject", &vars), "Basil: came online {{unknown}}");
+        assert!(mailer.render("email_body", &[("name", "Alice".to_string())]).starts_with("Hello Alice,"));
+    }
+
+    #[test]
+    fn summarises_events() {
+        let celsius = units(TemperatureUnit::Celsius, TankUnit::Percent);
+        assert_eq!(summary(&event(EventKind::State, json!({ "state": "watering" })), &celsius).as_deref(), Some("changed to watering"));
+        assert_eq!(summary(&event(EventKind::Online, json!({})), &celsius).as_deref(), Some("came online"));
+        assert_eq!(summary(&event(EventKind::Fault, json!({ "metric": "tank_fill", "fault": "out_of_range", "control_suppressed": true })), &celsius).as_deref(), Some("reports a faulty tank fill reading (out of range), automatic watering is paused"));
+        assert_eq!(summary(&event(EventKind::Data, json!({ "moisture": 42.0 })), &celsius), None);
+        assert_eq!(summary(&event(EventKind::State, json!({})), &celsius), None);
+    }
+
+    #[test]
+    fn summarises_alerts_in_the_preferred_units() {
+        let alert = |status: &str| event(EventKind::Alert, json!({ "metric": "temperature", "comparison": ">", "threshold": 30.0, "value": 35.0, "status": status }));
+        let fahrenheit = units(TemperatureUnit::Fahrenheit, TankUnit::Percent);
+        assert_eq!(summary(&alert("firing"), &fahrenheit).as_deref(), Some("reports temperature > 86 °F (95 °F)"));
+        assert_eq!(summary(&alert("resolved"), &units(TemperatureUnit::Celsius, TankUnit::Percent)).as_deref(), Some("no longer reports temperature > 30 °C (35 °C)"));
+    }
+
+    #[test]
+    fn formats_readings() {
+        let preferred = units(TemperatureUnit::Fahrenheit, TankUnit::Litres);
+        assert_eq!(reading("temperature", &json!(20.0), &preferred).as_deref(), Some("68 °F"));
+        assert_eq!(reading("tank_fill", &json!(50.0), &preferred).as_deref(), Some("5 l"));
+        assert_eq!(reading("moisture", &json!(42.5), &preferred).as_deref(), Some("42.5 %"));
+        assert_eq!(reading("tank_empty_in", &json!(12.0), &preferred).as_deref(), Some("12 h"));
+        assert_eq!(reading("moisture", &json!("wet"), &preferred), None);
+    }
+}
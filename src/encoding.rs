@@ -0,0 +1,107 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::Read;
+use std::ops::Deref;
+
+use rocket::Outcome;
+use rocket::data::{self, Data, FromDataSimple};
+use rocket::http::Status;
+use rocket::request::Request;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::validation::{self, FieldError, Validate};
+
+const DEFAULT_LIMIT: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+    MessagePack
+}
+
+impl Encoding {
+    // Protocol messages are always maps, and the map markers of CBOR
+    // (0xa0 - 0xbf) and MessagePack (0x80 - 0x8f, 0xde, 0xdf) don't overlap.
+    pub fn detect(data: &[u8], binary: bool) -> Self {
+        match data.first() {
+            Some(b) if binary && (0xa0..=0xbf).contains(b) => Encoding::Cbor,
+            Some(_) if binary => Encoding::MessagePack,
+            _ => Encoding::Json
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        *self != Encoding::Json
+    }
+
+    pub fn from_slice<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+            Encoding::Cbor => serde_cbor::from_slice(data).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::from_read(data).map_err(|e| e.to_string())
+        }
+    }
+
+    pub fn to_vec<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Encoding::Cbor => serde_cbor::to_vec(value).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string())
+        }
+    }
+}
+
+// Request body in JSON, CBOR (application/cbor) or MessagePack
+// (application/msgpack), chosen by the Content-Type header. The body is
+// validated like `Validated` bodies.
+#[derive(Debug)]
+pub struct Encoded<T>(pub T);
+
+impl<T> Deref for Encoded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate> FromDataSimple for Encoded<T> {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let encoding = match request.content_type() {
+            Some(ct) if ct.top() == "application" && ct.sub() == "cbor" => Encoding::Cbor,
+            Some(ct) if ct.top() == "application" && (ct.sub() == "msgpack" || ct.sub() == "x-msgpack") => Encoding::MessagePack,
+            _ => Encoding::Json
+        };
+
+        let limit = request.limits().get("json").unwrap_or(DEFAULT_LIMIT);
+        let mut buf = Vec::new();
+        if let Err(e) = data.open().take(limit).read_to_end(&mut buf) {
+            return Outcome::Failure((Status::BadRequest, e.to_string()));
+        }
+
+        match encoding.from_slice(&buf) {
+            Ok(value) => validation::check(request, value).map(Encoded),
+            Err(e) => Outcome::Failure((validation::reject(request, vec![FieldError::new("", &e)]), e))
+        }
+    }
+}
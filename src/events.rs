@@ -0,0 +1,222 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::{Mutex, Arc};
+use std::time::{Duration, Instant, SystemTime};
+use std::thread;
+
+use rocket::Outcome;
+use rocket::request::{self, FromRequest, Request};
+
+const EVENT_BACKLOG: usize = 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(1000);
+const KEEPALIVE_INTERVAL: Duration = Duration::new(30, 0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Data,
+    State,
+    Conf,
+    Online,
+    Offline,
+    Alert,
+    Outage,
+    Recovered,
+    Fault
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Data => "data",
+            EventKind::State => "state",
+            EventKind::Conf => "conf",
+            EventKind::Online => "online",
+            EventKind::Offline => "offline",
+            EventKind::Alert => "alert",
+            EventKind::Outage => "outage",
+            EventKind::Recovered => "recovered",
+            EventKind::Fault => "fault"
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub id: u64,
+    pub station: usize,
+    pub kind: EventKind,
+    pub data: String
+}
+
+// Event ids start at the current time in milliseconds so that ids handed out
+// before a restart are still smaller than the ones handed out after it.
+//
+// Every open stream occupies one of Rocket's workers for as long as the client
+// stays connected, so their number is limited.
+pub struct EventLog {
+    next_id: u64,
+    events: VecDeque<Event>,
+    streams: usize,
+    max_streams: usize
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        Self {
+            next_id: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64,
+            events: VecDeque::new(),
+            streams: 0,
+            max_streams: 0
+        }
+    }
+
+    pub fn limit_streams(&mut self, max_streams: usize) {
+        self.max_streams = max_streams;
+    }
+
+    pub fn push(&mut self, station: usize, kind: EventKind, data: String) {
+        self.events.push_back(Event {
+            id: self.next_id,
+            station,
+            kind,
+            data
+        });
+        self.next_id += 1;
+
+        if self.events.len() > EVENT_BACKLOG {
+            self.events.pop_front();
+        }
+    }
+
+    pub fn since(&self, station: usize, last_id: u64) -> Vec<Event> {
+        self.events.iter().filter(|e| e.station == station && e.id > last_id).cloned().collect()
+    }
+
+    pub fn all_since(&self, last_id: u64) -> Vec<Event> {
+        self.events.iter().filter(|e| e.id > last_id).cloned().collect()
+    }
+
+    pub fn last_id(&self) -> u64 {
+        self.next_id - 1
+    }
+
+    fn open_stream(&mut self) -> bool {
+        if self.streams < self.max_streams {
+            self.streams += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Rocket only flushes a chunked body when the reader reports WouldBlock (with
+// the `sse` feature), so one is returned after every batch of events that
+// didn't fill the chunk buffer completely.
+pub struct EventStream {
+    events: Arc<Mutex<EventLog>>,
+    station: usize,
+    last_id: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    flush: bool,
+    last_write: Instant
+}
+
+impl EventStream {
+    // Returns None if the maximum number of streams is already open.
+    pub fn open(events: Arc<Mutex<EventLog>>, station: usize, last_id: u64) -> Option<Self> {
+        if !events.lock().ok()?.open_stream() {
+            return None;
+        }
+
+        Some(Self {
+            events,
+            station,
+            last_id,
+            buf: b"retry: 5000\n\n".to_vec(),
+            pos: 0,
+            flush: false,
+            last_write: Instant::now()
+        })
+    }
+
+    fn poll(&mut self) -> io::Result<()> {
+        let events = self.events.lock().or(Err(io::Error::from(io::ErrorKind::Other)))?.since(self.station, self.last_id);
+        self.buf.clear();
+        self.pos = 0;
+
+        if let Some(last) = events.last() {
+            self.last_id = last.id;
+            for e in events.iter() {
+                self.buf.extend_from_slice(format!("id: {}\nevent: {}\ndata: {}\n\n", e.id, e.kind.name(), e.data).as_bytes());
+            }
+            self.last_write = Instant::now();
+        } else if self.last_write.elapsed() >= KEEPALIVE_INTERVAL {
+            self.buf.extend_from_slice(b": keepalive\n\n");
+            self.last_write = Instant::now();
+        } else {
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for EventStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.buf.len() {
+                let n = out.len().min(self.buf.len() - self.pos);
+                out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+                self.pos += n;
+                self.flush = self.pos == self.buf.len() && n < out.len();
+                return Ok(n);
+            }
+
+            if self.flush {
+                self.flush = false;
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+
+            self.poll()?;
+        }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        if let Ok(mut events) = self.events.lock() {
+            events.streams -= 1;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LastEventId(pub Option<u64>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for LastEventId {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(LastEventId(request.headers().get_one("Last-Event-ID").and_then(|id| id.parse().ok())))
+    }
+}
@@ -0,0 +1,307 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Rocket 0.4 can't hand a connection over after an upgrade, so when the
+// WebSocket endpoint is served on the HTTP port this listener sits in front of
+// Rocket. It takes over Rocket's configured address and port, terminates TLS,
+// hands upgrade requests for `WS_PATH` to the notifier and forwards everything
+// else to Rocket on a loopback port. TLS is configured with `ws_tls_cert` and
+// `ws_tls_key`, Rocket's own TLS can't be used behind it.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use std::thread;
+
+use rocket::config::RocketConfig;
+use websocket::sync::stream::AsTcpStream;
+
+use crate::ws_listener::*;
+
+const HTTP_INTERNAL_PORT: u16 = 8002;
+const WS_PATH: &str = "/v1/ws";
+const HEAD_LIMIT: usize = 8192;
+const HEAD_TIMEOUT: Duration = Duration::new(5, 0);
+const CONNECTIONS_MAX: usize = 256;
+const BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+enum Body {
+    Length(u64),
+    Chunked
+}
+
+pub fn internal_port(conf: &HashMap<String, String>) -> u16 {
+    conf.get("http_internal_port").and_then(|p| p.parse().ok()).unwrap_or(HTTP_INTERNAL_PORT)
+}
+
+pub fn run(conf: HashMap<String, String>, upgrades: Sender<WsClient>) {
+    let rocket = RocketConfig::read().or_else(|_| RocketConfig::active_default()).unwrap().active().clone();
+    let (host, port) = (rocket.address.as_str(), rocket.port);
+    let backend = internal_port(&conf);
+    let mut tls = Tls::from_conf(&conf);
+
+    let listener = TcpListener::bind((host, port)).unwrap();
+    println!("[HTTP]: Listening on {}://{}:{}", if tls.is_some() { "https" } else { "http" }, host, port);
+
+    // Every connection has its own thread, which ends once the request is
+    // answered or the connection is handed to the notifier.
+    let open = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue
+        };
+        if open.load(Ordering::SeqCst) >= CONNECTIONS_MAX {
+            println!("[HTTP]: Too many connections, dropping {}", stream.peer_addr().map(|a| a.to_string()).unwrap_or_default());
+            continue;
+        }
+
+        if let Some(tls) = tls.as_mut() {
+            tls.reload();
+        }
+        let stream: WsStream = match &tls {
+            Some(tls) => tls.wrap(stream),
+            None => Box::new(stream)
+        };
+
+        let upgrades = upgrades.clone();
+        let open = open.clone();
+        open.fetch_add(1, Ordering::SeqCst);
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, backend, upgrades) {
+                println!("[HTTP]: Connection failed ({})", e);
+            }
+            open.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn handle(mut stream: WsStream, backend: u16, upgrades: Sender<WsClient>) -> io::Result<()> {
+    stream.as_tcp().set_read_timeout(Some(HEAD_TIMEOUT))?;
+    let head = read_head(&mut stream)?;
+
+    if is_upgrade(&head) {
+        match upgrade(Box::new(Prefixed { prefix: head, pos: 0, inner: stream })) {
+            Some(cli) => upgrades.send(cli).or(Err(io::Error::from(io::ErrorKind::BrokenPipe))),
+            None => Ok(())
+        }
+    } else {
+        proxy(stream, &head, backend)
+    }
+}
+
+// Reads until the end of the head, which may be followed by the start of the body.
+fn read_head<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > HEAD_LIMIT {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    Ok(head)
+}
+
+fn is_upgrade(head: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+
+    let path = lines.next().and_then(|l| l.split(' ').nth(1)).unwrap_or("");
+    let upgrade = lines.take_while(|l| !l.is_empty())
+        .filter_map(|l| l.split_once(':'))
+        .any(|(k, v)| k.trim().eq_ignore_ascii_case("upgrade") && v.trim().eq_ignore_ascii_case("websocket"));
+
+    path.split('?').next() == Some(WS_PATH) && upgrade
+}
+
+// A TLS stream can't be shared between threads, so one request is forwarded
+// per connection and both the client and Rocket are told to close it
+// afterwards. Both directions are then plain blocking copies: the request
+// body, which is delimited by its length or its chunks, and the response until
+// Rocket closes the connection.
+fn proxy(client: WsStream, head: &[u8], backend: u16) -> io::Result<()> {
+    let addr = client.as_tcp().peer_addr()?.ip();
+    let end = head.windows(4).position(|w| w == b"\r\n\r\n").map(|p| p + 4).unwrap_or(head.len());
+    let (request, body) = head.split_at(end);
+    let mut client = BufReader::new(Prefixed { prefix: body.to_vec(), pos: 0, inner: client });
+
+    let (request, body) = match forward_head(&String::from_utf8_lossy(request), addr) {
+        Some(forwarded) => forwarded,
+        None => return client.get_mut().write_all(BAD_REQUEST)
+    };
+
+    let mut server = TcpStream::connect(("127.0.0.1", backend))?;
+    server.write_all(request.as_bytes())?;
+    match body {
+        Body::Length(length) => {
+            io::copy(&mut Read::take(&mut client, length), &mut server)?;
+        }
+        Body::Chunked => copy_chunked(&mut client, &mut server)?
+    }
+
+    let head = read_head(&mut server)?;
+    let end = head.windows(4).position(|w| w == b"\r\n\r\n").map(|p| p + 4).unwrap_or(head.len());
+    let (response, body) = head.split_at(end);
+    let client = client.get_mut();
+    client.write_all(close_head(&String::from_utf8_lossy(response)).as_bytes())?;
+    client.write_all(body)?;
+    io::copy(&mut server, client)?;
+    client.flush()
+}
+
+// Rewrites the request head for Rocket and returns it with the framing of the
+// body. Rocket takes the client address from `X-Real-IP`, so that header is
+// replaced rather than trusted. A chunked body takes precedence over a length.
+fn forward_head(head: &str, addr: IpAddr) -> Option<(String, Body)> {
+    let mut lines = head.split("\r\n").filter(|l| !l.is_empty());
+    let mut forwarded = format!("{}\r\n", lines.next()?);
+    let mut length = None;
+    let mut chunked = false;
+    let mut forwarded_for = addr.to_string();
+
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => continue
+        };
+        match name.as_str() {
+            "transfer-encoding" if value.eq_ignore_ascii_case("chunked") => chunked = true,
+            "transfer-encoding" => return None,
+            "content-length" => {
+                length = Some(value.parse().ok()?);
+                continue;
+            }
+            "x-forwarded-for" => {
+                forwarded_for = format!("{}, {}", value, addr);
+                continue;
+            }
+            "x-real-ip" | "connection" | "keep-alive" => continue,
+            _ => ()
+        }
+        forwarded.push_str(line);
+        forwarded.push_str("\r\n");
+    }
+
+    let body = match (chunked, length) {
+        (true, _) => Body::Chunked,
+        (false, Some(length)) => {
+            forwarded.push_str(&format!("Content-Length: {}\r\n", length));
+            Body::Length(length)
+        }
+        (false, None) => Body::Length(0)
+    };
+    forwarded.push_str(&format!("X-Forwarded-For: {}\r\nX-Real-IP: {}\r\nConnection: close\r\n\r\n", forwarded_for, addr));
+    Some((forwarded, body))
+}
+
+// Copies a chunked body with its framing, which Rocket decodes itself.
+fn copy_chunked<R: BufRead, W: Write>(client: &mut R, server: &mut W) -> io::Result<()> {
+    loop {
+        let line = read_line(client)?;
+        server.write_all(line.as_bytes())?;
+        let size = line.split(';').next().and_then(|s| u64::from_str_radix(s.trim(), 16).ok())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+        if size == 0 {
+            break;
+        }
+        if io::copy(&mut client.by_ref().take(size + 2), server)? < size + 2 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+    }
+
+    // The trailers end with an empty line.
+    loop {
+        let line = read_line(client)?;
+        server.write_all(line.as_bytes())?;
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+fn read_line<R: BufRead>(client: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if client.by_ref().take(HEAD_LIMIT as u64).read_line(&mut line)? == 0 || !line.ends_with('\n') {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+    Ok(line)
+}
+
+// Rocket's response head, telling the client that the connection closes after it.
+fn close_head(head: &str) -> String {
+    let mut lines = head.split("\r\n").filter(|l| !l.is_empty());
+    let mut closed = format!("{}\r\n", lines.next().unwrap_or(""));
+    for line in lines {
+        let name = line.split(':').next().unwrap_or("").trim();
+        if !name.eq_ignore_ascii_case("connection") && !name.eq_ignore_ascii_case("keep-alive") {
+            closed.push_str(line);
+            closed.push_str("\r\n");
+        }
+    }
+    closed.push_str("Connection: close\r\n\r\n");
+    closed
+}
+
+// Replays the request head that was read to decide where the connection goes.
+struct Prefixed {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: WsStream
+}
+
+impl Read for Prefixed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.prefix.len() {
+            let n = buf.len().min(self.prefix.len() - self.pos);
+            buf[..n].copy_from_slice(&self.prefix[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        } else {
+            self.inner.read(buf)
+        }
+    }
+}
+
+impl Write for Prefixed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsTcpStream for Prefixed {
+    fn as_tcp(&self) -> &TcpStream {
+        self.inner.as_tcp()
+    }
+}
@@ -0,0 +1,88 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::sync::{Mutex, Arc};
+
+use mysql::PooledConn;
+use rocket::http::Status;
+use serde_json::json;
+
+use crate::model::*;
+use crate::alerts::{self, Metric};
+use crate::anomalies;
+use crate::calibration;
+use crate::bus::Bus;
+use crate::control;
+use crate::events::*;
+use crate::units;
+
+pub fn ingest(station: usize, req: &DataReq, conf: &HashMap<String, String>, db: &mut PooledConn, reqs: &Arc<Mutex<Box<dyn Bus>>>, events: &Arc<Mutex<EventLog>>) -> Result<DataRow, Status> {
+    let calibrated = calibration::calibrate(station, req, db)?;
+    let d = add_data(station, &calibrated, req, db)?;
+    // The reading is already stored, so this failure isn't passed on.
+    if touch_station(station, d.time, db).is_err() {
+        println!("[WATCHDOG]: Failed to record that station {} was seen", station);
+    }
+
+    let mut data = serde_json::to_value(&DataElement {
+        time: d.time,
+        moisture: d.moisture,
+        temperature: d.temperature,
+        humidity: d.humidity,
+        tank_fill: d.tank_fill,
+        raw: units::raw_element(&d)
+    }).or(Err(Status::InternalServerError))?;
+    data["units"] = units::event_units();
+    events.lock().or(Err(Status::InternalServerError))?.push(station, EventKind::Data, data.to_string());
+
+    // The reading is stored either way, so these failures aren't passed on.
+    let faults = match anomalies::check(&d, conf, db) {
+        Ok(faults) => faults,
+        Err(_) => {
+            println!("[ANOMALY]: Failed to check the reading of station {}", station);
+            Vec::new()
+        }
+    };
+
+    // A faulty moisture probe shouldn't make the station water, which is
+    // reported so that a disabled policy doesn't go unnoticed.
+    let controlled = get_control_policy(station, db).ok().flatten().map(|p| p.enabled).unwrap_or(false);
+    for (metric, fault) in faults.iter() {
+        let suppressed = controlled && *metric == Metric::Moisture;
+        println!("[ANOMALY]: Station {} reported a suspicious {} ({}){}", station, metric.as_str(), fault.as_str(), if suppressed { ", control suppressed" } else { "" });
+        let data = json!({
+            "metric": metric,
+            "fault": fault,
+            "value": metric.value(&d),
+            "units": units::event_units(),
+            "control_suppressed": suppressed
+        });
+        events.lock().or(Err(Status::InternalServerError))?.push(station, EventKind::Fault, data.to_string());
+    }
+
+    let clean = anomalies::without_faults(&d, &faults);
+    if alerts::evaluate(&clean, db, events).is_err() {
+        println!("[ALERTS]: Failed to evaluate the alert rules of station {}", station);
+    }
+    if control::evaluate(&clean, db, reqs, events).is_err() {
+        println!("[CONTROL]: Failed to evaluate the policy of station {}", station);
+    }
+
+    Ok(d)
+}
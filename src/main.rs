@@ -23,17 +23,40 @@
 use std::thread;
 use std::collections::HashMap;
 use std::sync::{Mutex, Arc};
+use std::sync::mpsc;
 
 use mysql::*;
 
 #[macro_use] extern crate rocket;
 
+mod alerts;
+mod analytics;
+mod anomalies;
 mod apiv1;
 mod auth;
+mod bus;
+mod calibration;
+mod control;
+mod email;
+mod encoding;
+mod events;
+mod http_mux;
+mod ingest;
 mod model;
+mod plants;
+mod schedule;
+mod state;
+mod station_conf;
+mod units;
+mod validation;
+mod watchdog;
+mod webhooks;
+mod ws_listener;
 mod ws_notifier;
+mod ws_protocol;
 
-use ws_notifier::WsRequest;
+use events::EventLog;
+use ws_listener::ListenMode;
 
 const CONFIG_FILE: &str = "/etc/stomata/conf.toml";
 
@@ -53,22 +76,81 @@ fn main() {
     //conn.query_drop(&format!("CREATE DATABASE IF NOT EXISTS {}", &conf["db_name"])).unwrap();
     //conn.query_drop(&format!("USE {}", &conf["db_name"])).unwrap();
     model::create_tables(&mut conn);
+    plants::import(&conf, &mut conn);
+
+    let reqs = bus::from_conf(&conf, &db);
+    let reqs = Arc::new(Mutex::new(reqs));
 
     let db = Arc::new(Mutex::new(db));
 
-    let reqs: Vec<WsRequest> = Vec::new();
-    let reqs = Arc::new(Mutex::new(reqs));
+    let events = Arc::new(Mutex::new(EventLog::new()));
+
+    let conf_ws = conf.clone();
+    let conf_sched = conf.clone();
+    let conf_control = conf.clone();
+    let conf_email = conf.clone();
+    let conf_watchdog = conf.clone();
+
+    let listen_mode = ListenMode::from_conf(&conf);
+    let (upgrades_tx, upgrades) = mpsc::channel();
+    if listen_mode.standalone() {
+        let conf_listener = conf.clone();
+        let upgrades_listener = upgrades_tx.clone();
+        thread::spawn(move || {
+            ws_listener::run(conf_listener, upgrades_listener);
+        });
+    }
+    if listen_mode.http() {
+        let conf_mux = conf.clone();
+        thread::spawn(move || {
+            http_mux::run(conf_mux, upgrades_tx);
+        });
+    }
 
     let db_http = db.clone();
     let reqs_http = reqs.clone();
+    let events_http = events.clone();
     let http_server = thread::spawn(move || {
-        apiv1::run(db_http, conf, reqs_http);
+        apiv1::run(db_http, conf, reqs_http, events_http);
+    });
+
+    let db_sched = db.clone();
+    let reqs_sched = reqs.clone();
+    let events_sched = events.clone();
+    thread::spawn(move || {
+        schedule::run(db_sched, conf_sched, reqs_sched, events_sched);
+    });
+
+    let db_control = db.clone();
+    let reqs_control = reqs.clone();
+    let events_control = events.clone();
+    thread::spawn(move || {
+        control::run(db_control, conf_control, reqs_control, events_control);
+    });
+
+    let db_hooks = db.clone();
+    let events_hooks = events.clone();
+    thread::spawn(move || {
+        webhooks::run(db_hooks, events_hooks);
+    });
+
+    let db_email = db.clone();
+    let events_email = events.clone();
+    thread::spawn(move || {
+        email::run(db_email, conf_email, events_email);
+    });
+
+    let db_watchdog = db.clone();
+    let events_watchdog = events.clone();
+    thread::spawn(move || {
+        watchdog::run(db_watchdog, conf_watchdog, events_watchdog);
     });
 
     let db_ws = db.clone();
     let reqs_ws = reqs.clone();
+    let events_ws = events.clone();
     let ws_server = thread::spawn(move || {
-        ws_notifier::run(db_ws, reqs_ws);
+        ws_notifier::run(db_ws, conf_ws, upgrades, reqs_ws, events_ws);
     });
 
     http_server.join().unwrap();
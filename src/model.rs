@@ -16,95 +16,935 @@
  * along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
-use mysql::PooledConn;
+use mysql::{PooledConn, TxOpts};
 use mysql::prelude::Queryable;
 use rocket::http::Status;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::alerts::{Comparison, Metric};
+use crate::anomalies::Fault;
+use crate::state::{Actor, StationState};
+use crate::station_conf::{conf_string, parse_legacy_conf};
+use crate::units::{TankUnit, TemperatureUnit};
+use crate::validation::FieldError;
+
+// Attempts at taking the next configuration revision before giving up.
+const REVISION_ATTEMPTS: usize = 5;
+const ER_DUP_ENTRY: u16 = 1062;
 
 #[derive(Debug)]
 pub struct UserRow {
     pub login: String,
     pub name: String,
-    pub pass: String
+    pub pass: String,
+    pub email: Option<String>
+}
+
+#[derive(Debug)]
+pub struct NotificationPrefsRow {
+    pub login: String,
+    pub events: Vec<String>,
+    pub digest: bool,
+    pub digest_interval: u64
 }
 
 #[derive(Debug)]
 pub struct StationRow {
     pub id: usize,
     pub name: String,
-    pub state: String,
+    pub state: StationState,
     pub owner: Option<String>,
     pub token: String,
-    pub conf: Option<String>
+    pub conf: Option<Value>,
+    pub schema: Option<Value>,
+    pub conf_acked: Option<u64>,
+    pub tank_capacity: Option<f32>,
+    pub plant: Option<String>,
+    pub unknown_state: Option<String>
+}
+
+#[derive(Debug)]
+pub struct ConfRevisionRow {
+    pub station: usize,
+    pub revision: u64,
+    pub time: usize,
+    pub actor: String,
+    pub login: Option<String>,
+    pub conf: Value
+}
+
+#[derive(Debug)]
+pub struct ScheduleRow {
+    pub id: u64,
+    pub station: usize,
+    pub times: Vec<String>,
+    pub days: Vec<String>,
+    pub duration: u64,
+    pub timezone: String,
+    pub enabled: bool
+}
+
+#[derive(Debug)]
+pub struct ScheduleRunRow {
+    pub id: u64,
+    pub schedule: u64,
+    pub station: usize,
+    pub time: usize,
+    pub outcome: String,
+    pub ends: Option<usize>,
+    pub active: bool
+}
+
+#[derive(Debug)]
+pub struct ControlPolicyRow {
+    pub station: usize,
+    pub enabled: bool,
+    pub dry_run: bool,
+    pub moisture_low: f32,
+    pub moisture_high: f32,
+    pub max_duration: u64,
+    pub min_interval: u64,
+    pub tank_min: Option<f32>,
+    pub last_start: Option<usize>,
+    pub active: bool,
+    pub simulated: bool
+}
+
+#[derive(Debug)]
+pub struct AlertRuleRow {
+    pub id: u64,
+    pub station: usize,
+    pub metric: Metric,
+    pub comparison: Comparison,
+    pub threshold: f32,
+    pub duration: u64,
+    pub cooldown: u64,
+    pub since: Option<usize>,
+    pub firing: bool,
+    pub last_fired: Option<usize>
+}
+
+#[derive(Debug)]
+pub struct AlertRow {
+    pub id: u64,
+    pub rule: u64,
+    pub station: usize,
+    pub value: f32,
+    pub fired: usize,
+    pub resolved: Option<usize>
+}
+
+#[derive(Debug)]
+pub struct WebhookRow {
+    pub id: u64,
+    pub login: String,
+    pub url: String,
+    pub events: Vec<String>,
+    pub secret: String,
+    pub enabled: bool
+}
+
+#[derive(Debug)]
+pub struct WebhookDeliveryRow {
+    pub id: u64,
+    pub webhook: u64,
+    pub event: u64,
+    pub kind: String,
+    pub time: usize,
+    pub attempt: u32,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub body: Option<String>,
+    pub next_try: Option<usize>
+}
+
+#[derive(Debug)]
+pub struct TankPredictionRow {
+    pub station: usize,
+    pub computed: usize,
+    pub empty_at: Option<usize>
+}
+
+#[derive(Debug)]
+pub struct StationStatusRow {
+    pub station: usize,
+    pub last_seen: usize,
+    pub offline: bool
+}
+
+#[derive(Debug)]
+pub struct OutageRow {
+    pub id: u64,
+    pub station: usize,
+    pub started: usize,
+    pub ended: Option<usize>
 }
 
 #[derive(Debug)]
+pub struct HistoryRow {
+    pub id: u64,
+    pub station: usize,
+    pub time: usize,
+    pub actor: String,
+    pub login: Option<String>,
+    pub kind: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub source: String
+}
+
+#[derive(Debug)]
+pub struct DataFaultRow {
+    pub id: u64,
+    pub station: usize,
+    pub time: usize,
+    pub metric: Metric,
+    pub fault: Fault,
+    pub value: f32
+}
+
+#[derive(Debug, Clone)]
 pub struct DataRow {
     pub station: usize,
     pub time: usize,
     pub moisture: Option<f32>,
     pub temperature: Option<f32>,
     pub humidity: Option<f32>,
-    pub tank_fill: Option<f32>
+    pub tank_fill: Option<f32>,
+    pub moisture_raw: Option<f32>,
+    pub temperature_raw: Option<f32>,
+    pub humidity_raw: Option<f32>,
+    pub tank_fill_raw: Option<f32>
+}
+
+#[derive(Debug)]
+pub struct CalibrationRow {
+    pub station: usize,
+    pub metric: Metric,
+    pub points: Vec<(f32, f32)>
+}
+
+#[derive(Debug, Default)]
+pub struct StationMetaRow {
+    pub station: usize,
+    pub location: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub timezone: Option<String>,
+    pub indoor: Option<bool>,
+    pub notes: Option<String>,
+    pub photo: Option<String>
+}
+
+#[derive(Debug)]
+pub struct GardenRow {
+    pub id: u64,
+    pub login: String,
+    pub name: String,
+    pub stations: Vec<usize>
+}
+
+#[derive(Debug)]
+pub struct PlantRow {
+    pub id: String,
+    pub name: String,
+    pub species: String,
+    pub moisture: (f32, f32),
+    pub temperature: (f32, f32),
+    pub humidity: (f32, f32),
+    pub watering: String
+}
+
+#[derive(Debug)]
+pub struct UnitPrefsRow {
+    pub login: String,
+    pub temperature: TemperatureUnit,
+    pub tank_fill: TankUnit
 }
 
 pub fn create_tables(db: &mut PooledConn) {
-    db.query_drop("CREATE TABLE IF NOT EXISTS users (login TEXT NOT NULL, name TEXT NOT NULL, pass TEXT NOT NULL)").unwrap();
-    db.query_drop("CREATE TABLE IF NOT EXISTS stations (id INT NOT NULL, name TEXT NOT NULL, state TEXT NOT NULL, owner TEXT, token TEXT NOT NULL, conf TEXT)").unwrap();
-    db.query_drop("CREATE TABLE IF NOT EXISTS data (station INT NOT NULL, time INT NOT NULL, moisture FLOAT, temperature FLOAT, humidity FLOAT, tank_fill FLOAT)").unwrap();
+    // Stations didn't have a timezone before their meta data was stored.
+    let station_meta_exists = db.query_first::<u8, _>("SELECT 1 FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = 'station_meta'").unwrap().is_some();
+
+    db.query_drop("CREATE TABLE IF NOT EXISTS users (login TEXT NOT NULL, name TEXT NOT NULL, pass TEXT NOT NULL, email TEXT)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS stations (id INT NOT NULL, name TEXT NOT NULL, state TEXT NOT NULL, owner TEXT, token TEXT NOT NULL, conf TEXT, conf_schema TEXT, conf_acked BIGINT, tank_capacity FLOAT, plant VARCHAR(64), conf_json TEXT)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS data (station INT NOT NULL, time INT NOT NULL, moisture FLOAT, temperature FLOAT, humidity FLOAT, tank_fill FLOAT, moisture_raw FLOAT, temperature_raw FLOAT, humidity_raw FLOAT, tank_fill_raw FLOAT)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS history (id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY, station INT NOT NULL, time INT NOT NULL, actor TEXT NOT NULL, login TEXT, kind TEXT NOT NULL, old_value TEXT, new_value TEXT, source TEXT NOT NULL)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS conf_revisions (station INT NOT NULL, revision BIGINT NOT NULL, time INT NOT NULL, actor TEXT NOT NULL, login TEXT, conf TEXT NOT NULL, PRIMARY KEY (station, revision))").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS schedules (id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY, station INT NOT NULL, times TEXT NOT NULL, days TEXT NOT NULL, duration BIGINT NOT NULL, timezone TEXT NOT NULL, enabled BOOL NOT NULL, created INT)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS schedule_runs (id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY, schedule BIGINT NOT NULL, station INT NOT NULL, time INT NOT NULL, outcome TEXT NOT NULL, ends INT, active BOOL NOT NULL DEFAULT FALSE)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS control_policies (station INT NOT NULL PRIMARY KEY, enabled BOOL NOT NULL, dry_run BOOL NOT NULL, moisture_low FLOAT NOT NULL, moisture_high FLOAT NOT NULL, max_duration BIGINT NOT NULL, min_interval BIGINT NOT NULL, tank_min FLOAT, last_start INT, active BOOL NOT NULL, simulated BOOL NOT NULL DEFAULT FALSE)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS alert_rules (id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY, station INT NOT NULL, metric TEXT NOT NULL, comparison TEXT NOT NULL, threshold FLOAT NOT NULL, duration BIGINT NOT NULL, cooldown BIGINT NOT NULL, since INT, firing BOOL NOT NULL, last_fired INT)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS alerts (id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY, rule BIGINT NOT NULL, station INT NOT NULL, value FLOAT NOT NULL, fired INT NOT NULL, resolved INT)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS webhooks (id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY, login TEXT NOT NULL, url TEXT NOT NULL, events TEXT NOT NULL, secret TEXT NOT NULL, enabled BOOL NOT NULL)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS webhook_deliveries (id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY, webhook BIGINT NOT NULL, event BIGINT NOT NULL, kind TEXT NOT NULL, time INT NOT NULL, attempt INT NOT NULL, status INT, error TEXT, body TEXT, next_try INT)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS notification_prefs (login VARCHAR(255) NOT NULL PRIMARY KEY, events TEXT NOT NULL, digest BOOL NOT NULL, digest_interval BIGINT NOT NULL)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS station_status (station INT NOT NULL PRIMARY KEY, last_seen INT NOT NULL, offline BOOL NOT NULL)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS outages (id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY, station INT NOT NULL, started INT NOT NULL, ended INT)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS calibrations (station INT NOT NULL, metric VARCHAR(32) NOT NULL, points TEXT NOT NULL, PRIMARY KEY (station, metric))").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS station_meta (station INT NOT NULL PRIMARY KEY, location TEXT, latitude DOUBLE, longitude DOUBLE, timezone TEXT, indoor BOOL, notes TEXT, photo TEXT)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS gardens (id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY, login TEXT NOT NULL, name TEXT NOT NULL)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS garden_stations (garden BIGINT NOT NULL, station INT NOT NULL, PRIMARY KEY (garden, station))").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS plants (id VARCHAR(64) NOT NULL PRIMARY KEY, name TEXT NOT NULL, species TEXT NOT NULL, moisture_min FLOAT NOT NULL, moisture_max FLOAT NOT NULL, temperature_min FLOAT NOT NULL, temperature_max FLOAT NOT NULL, humidity_min FLOAT NOT NULL, humidity_max FLOAT NOT NULL, watering TEXT NOT NULL)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS unit_prefs (login VARCHAR(255) NOT NULL PRIMARY KEY, temperature TEXT NOT NULL, tank_fill TEXT NOT NULL)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS data_faults (id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY, station INT NOT NULL, time INT NOT NULL, metric TEXT NOT NULL, fault TEXT NOT NULL, value FLOAT NOT NULL)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS tank_predictions (station INT NOT NULL PRIMARY KEY, computed INT NOT NULL, empty_at INT)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS ws_presence (station INT NOT NULL PRIMARY KEY, instance TEXT NOT NULL, seen INT)").unwrap();
+    db.query_drop("CREATE TABLE IF NOT EXISTS ws_bus (seq BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY, instance TEXT NOT NULL, time INT NOT NULL, request TEXT NOT NULL)").unwrap();
+
+    // Columns added to existing tables. These fail harmlessly once the column exists.
+    db.query_drop("ALTER TABLE stations ADD COLUMN conf_schema TEXT").ok();
+    db.query_drop("ALTER TABLE stations ADD COLUMN conf_acked BIGINT").ok();
+    db.query_drop("ALTER TABLE users ADD COLUMN email TEXT").ok();
+    db.query_drop("ALTER TABLE stations ADD COLUMN tank_capacity FLOAT").ok();
+    db.query_drop("ALTER TABLE stations ADD COLUMN plant VARCHAR(64)").ok();
+    db.query_drop("ALTER TABLE stations ADD COLUMN conf_json TEXT").ok();
+    db.query_drop("ALTER TABLE data ADD COLUMN moisture_raw FLOAT").ok();
+    db.query_drop("ALTER TABLE data ADD COLUMN temperature_raw FLOAT").ok();
+    db.query_drop("ALTER TABLE data ADD COLUMN humidity_raw FLOAT").ok();
+    db.query_drop("ALTER TABLE data ADD COLUMN tank_fill_raw FLOAT").ok();
+    db.query_drop("ALTER TABLE schedule_runs ADD COLUMN ends INT").ok();
+    // Runs used to be ended by clearing their end, so those that still have one are in progress.
+    if db.query_drop("ALTER TABLE schedule_runs ADD COLUMN active BOOL NOT NULL DEFAULT FALSE").is_ok() {
+        db.query_drop("UPDATE schedule_runs SET active = TRUE WHERE ends IS NOT NULL").unwrap();
+    }
+    db.query_drop("ALTER TABLE control_policies ADD COLUMN simulated BOOL NOT NULL DEFAULT FALSE").ok();
+    db.query_drop("ALTER TABLE webhook_deliveries ADD COLUMN body TEXT").ok();
+    db.query_drop("ALTER TABLE webhook_deliveries ADD COLUMN next_try INT").ok();
+    db.query_drop("ALTER TABLE ws_presence ADD COLUMN seen INT").ok();
+    // Schedules created before stations had a timezone stored the UTC default
+    // explicitly. They are blanked once so they follow their station's
+    // timezone, schedules created since then keep an explicit UTC.
+    if db.query_drop("ALTER TABLE schedules ADD COLUMN created INT").is_ok() && !station_meta_exists {
+        db.query_drop("UPDATE schedules SET timezone = '' WHERE timezone = 'UTC' AND created IS NULL").unwrap();
+    }
+}
+
+pub fn now() -> usize {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as usize
 }
 
 pub fn get_user(login: &str, db: &mut PooledConn) -> Result<UserRow, Status> {
     Ok(db.exec_first("SELECT * FROM users WHERE login = ?", (login,)).or(Err(Status::InternalServerError))?
-        .map(|(login, name, pass)| UserRow { login, name, pass }).ok_or(Status::NotFound)?)
+        .map(|(login, name, pass, email)| UserRow { login, name, pass, email }).ok_or(Status::NotFound)?)
+}
+
+pub fn get_notification_prefs(login: &str, db: &mut PooledConn) -> Result<Option<NotificationPrefsRow>, Status> {
+    Ok(db.exec_first("SELECT * FROM notification_prefs WHERE login = ?", (login,)).or(Err(Status::InternalServerError))?
+        .map(|(login, events, digest, digest_interval): (String, String, bool, u64)| NotificationPrefsRow {
+            login,
+            events: events.split(',').filter(|e| !e.is_empty()).map(str::to_string).collect(),
+            digest,
+            digest_interval
+        }))
+}
+
+// A state this version doesn't know is treated as idle, but kept in
+// `unknown_state` so that it is only replaced by an actual state change.
+// `conf` keeps the string representation for older versions, the configuration
+// itself is read from `conf_json` unless it was stored before that existed.
+fn station_row((id, name, state, owner, token, conf, schema, conf_acked, tank_capacity, plant, conf_json): (usize, String, String, Option<String>, String, Option<String>, Option<String>, Option<u64>, Option<f32>, Option<String>, Option<String>)) -> StationRow {
+    let (state, unknown_state) = match state.parse() {
+        Ok(state) => (state, None),
+        Err(_) => {
+            println!("[DB]: Station {} has the unknown state {:?}", id, state);
+            (StationState::Idle, Some(state))
+        }
+    };
+
+    StationRow {
+        id,
+        name,
+        state,
+        owner,
+        token,
+        conf: match conf_json {
+            Some(c) => serde_json::from_str(&c).ok(),
+            None => conf.map(parse_legacy_conf)
+        },
+        schema: schema.and_then(|s| serde_json::from_str(&s).ok()),
+        conf_acked,
+        tank_capacity,
+        plant,
+        unknown_state
+    }
+}
+
+fn plant_row((id, name, species, moisture_min, moisture_max, temperature_min, temperature_max, humidity_min, humidity_max, watering): (String, String, String, f32, f32, f32, f32, f32, f32, String)) -> PlantRow {
+    PlantRow {
+        id,
+        name,
+        species,
+        moisture: (moisture_min, moisture_max),
+        temperature: (temperature_min, temperature_max),
+        humidity: (humidity_min, humidity_max),
+        watering
+    }
+}
+
+// Readings stored before calibrations existed are their own raw values.
+fn data_row((station, time, moisture, temperature, humidity, tank_fill, moisture_raw, temperature_raw, humidity_raw, tank_fill_raw): (usize, usize, Option<f32>, Option<f32>, Option<f32>, Option<f32>, Option<f32>, Option<f32>, Option<f32>, Option<f32>)) -> DataRow {
+    DataRow {
+        station,
+        time,
+        moisture,
+        temperature,
+        humidity,
+        tank_fill,
+        moisture_raw: moisture_raw.or(moisture),
+        temperature_raw: temperature_raw.or(temperature),
+        humidity_raw: humidity_raw.or(humidity),
+        tank_fill_raw: tank_fill_raw.or(tank_fill)
+    }
+}
+
+fn conf_revision_row((station, revision, time, actor, login, conf): (usize, u64, usize, String, Option<String>, String)) -> ConfRevisionRow {
+    ConfRevisionRow {
+        station,
+        revision,
+        time,
+        actor,
+        login,
+        conf: serde_json::from_str(&conf).unwrap_or(Value::String(conf))
+    }
 }
 
 pub fn get_station(id: usize, db: &mut PooledConn) -> Result<StationRow, Status> {
     Ok(db.exec_first("SELECT * FROM stations WHERE id = ?", (id,)).or(Err(Status::InternalServerError))?
-        .map(|(id, name, state, owner, token, conf)| StationRow { id, name, state, owner, token, conf }).ok_or(Status::NotFound)?)
+        .map(station_row).ok_or(Status::NotFound)?)
 }
 
 pub fn get_stations(owner: &str, db: &mut PooledConn) -> Result<Vec<StationRow>, Status> {
     Ok(db.exec("SELECT * FROM stations WHERE owner = ?", (owner,)).or(Err(Status::InternalServerError))?
-        .into_iter().map(|(id, name, state, owner, token, conf)| StationRow { id, name, state, owner, token, conf }).collect())
+        .into_iter().map(station_row).collect())
 }
 
 pub fn get_data(station: usize, db: &mut PooledConn) -> Result<Vec<DataRow>, Status> {
     Ok(db.exec("SELECT * FROM data WHERE station = ?", (station,)).or(Err(Status::InternalServerError))?
-        .into_iter().map(|(station, time, moisture, temperature, humidity, tank_fill)| DataRow { station, time, moisture, temperature, humidity, tank_fill }).collect())
+        .into_iter().map(data_row).collect())
 }
 
 pub fn get_data_count(station: usize, count: usize, db: &mut PooledConn) -> Result<Vec<DataRow>, Status> {
     Ok(db.exec("SELECT * FROM data WHERE station = ? ORDER BY time DESC LIMIT ?", (station, count)).or(Err(Status::InternalServerError))?
-        .into_iter().map(|(station, time, moisture, temperature, humidity, tank_fill)| DataRow { station, time, moisture, temperature, humidity, tank_fill }).collect())
+        .into_iter().map(data_row).collect())
+}
+
+pub fn get_data_before(station: usize, before: usize, db: &mut PooledConn) -> Result<Option<DataRow>, Status> {
+    Ok(db.exec_first("SELECT * FROM data WHERE station = ? AND time < ? ORDER BY time DESC LIMIT 1", (station, before)).or(Err(Status::InternalServerError))?
+        .map(data_row))
+}
+
+pub fn get_data_since(station: usize, since: usize, db: &mut PooledConn) -> Result<Vec<DataRow>, Status> {
+    Ok(db.exec("SELECT * FROM data WHERE station = ? AND time >= ? ORDER BY time", (station, since)).or(Err(Status::InternalServerError))?
+        .into_iter().map(data_row).collect())
+}
+
+pub fn get_history(station: usize, before: Option<u64>, count: usize, db: &mut PooledConn) -> Result<Vec<HistoryRow>, Status> {
+    Ok(db.exec("SELECT * FROM history WHERE station = ? AND id < ? ORDER BY id DESC LIMIT ?", (station, before.unwrap_or(u64::MAX), count)).or(Err(Status::InternalServerError))?
+        .into_iter().map(|(id, station, time, actor, login, kind, old_value, new_value, source)| HistoryRow { id, station, time, actor, login, kind, old_value, new_value, source }).collect())
+}
+
+pub fn get_conf_revisions(station: usize, db: &mut PooledConn) -> Result<Vec<ConfRevisionRow>, Status> {
+    Ok(db.exec("SELECT * FROM conf_revisions WHERE station = ? ORDER BY revision DESC", (station,)).or(Err(Status::InternalServerError))?
+        .into_iter().map(conf_revision_row).collect())
+}
+
+pub fn get_conf_revision(station: usize, revision: u64, db: &mut PooledConn) -> Result<ConfRevisionRow, Status> {
+    Ok(db.exec_first("SELECT * FROM conf_revisions WHERE station = ? AND revision = ?", (station, revision)).or(Err(Status::InternalServerError))?
+        .map(conf_revision_row).ok_or(Status::NotFound)?)
+}
+
+// Times and days are stored as comma separated lists.
+fn schedule_row((id, station, times, days, duration, timezone, enabled, _created): (u64, usize, String, String, u64, String, bool, Option<usize>)) -> ScheduleRow {
+    let split = |s: String| s.split(',').filter(|s| !s.is_empty()).map(str::to_string).collect();
+    ScheduleRow { id, station, times: split(times), days: split(days), duration, timezone, enabled }
+}
+
+pub fn get_schedule(id: u64, db: &mut PooledConn) -> Result<ScheduleRow, Status> {
+    Ok(db.exec_first("SELECT * FROM schedules WHERE id = ?", (id,)).or(Err(Status::InternalServerError))?
+        .map(schedule_row).ok_or(Status::NotFound)?)
+}
+
+pub fn get_schedules(station: usize, db: &mut PooledConn) -> Result<Vec<ScheduleRow>, Status> {
+    Ok(db.exec("SELECT * FROM schedules WHERE station = ? ORDER BY id", (station,)).or(Err(Status::InternalServerError))?
+        .into_iter().map(schedule_row).collect())
+}
+
+pub fn get_enabled_schedules(db: &mut PooledConn) -> Result<Vec<ScheduleRow>, Status> {
+    Ok(db.query("SELECT * FROM schedules WHERE enabled").or(Err(Status::InternalServerError))?
+        .into_iter().map(schedule_row).collect())
+}
+
+pub fn get_schedule_runs(schedule: u64, count: usize, db: &mut PooledConn) -> Result<Vec<ScheduleRunRow>, Status> {
+    Ok(db.exec("SELECT * FROM schedule_runs WHERE schedule = ? ORDER BY id DESC LIMIT ?", (schedule, count)).or(Err(Status::InternalServerError))?
+        .into_iter().map(|(id, schedule, station, time, outcome, ends, active)| ScheduleRunRow { id, schedule, station, time, outcome, ends, active }).collect())
+}
+
+// Runs that were started and haven't been stopped yet. Their end is kept
+// once they are stopped.
+pub fn get_active_schedule_runs(db: &mut PooledConn) -> Result<Vec<ScheduleRunRow>, Status> {
+    Ok(db.query("SELECT * FROM schedule_runs WHERE active").or(Err(Status::InternalServerError))?
+        .into_iter().map(|(id, schedule, station, time, outcome, ends, active)| ScheduleRunRow { id, schedule, station, time, outcome, ends, active }).collect())
+}
+
+fn control_policy_row((station, enabled, dry_run, moisture_low, moisture_high, max_duration, min_interval, tank_min, last_start, active, simulated): (usize, bool, bool, f32, f32, u64, u64, Option<f32>, Option<usize>, bool, bool)) -> ControlPolicyRow {
+    ControlPolicyRow { station, enabled, dry_run, moisture_low, moisture_high, max_duration, min_interval, tank_min, last_start, active, simulated }
+}
+
+pub fn get_control_policy(station: usize, db: &mut PooledConn) -> Result<Option<ControlPolicyRow>, Status> {
+    Ok(db.exec_first("SELECT * FROM control_policies WHERE station = ?", (station,)).or(Err(Status::InternalServerError))?
+        .map(control_policy_row))
+}
+
+pub fn get_active_control_policies(db: &mut PooledConn) -> Result<Vec<ControlPolicyRow>, Status> {
+    Ok(db.query("SELECT * FROM control_policies WHERE enabled AND (active OR simulated)").or(Err(Status::InternalServerError))?
+        .into_iter().map(control_policy_row).collect())
+}
+
+// Rules with a metric or comparison this version doesn't know are skipped.
+fn alert_rule_row((id, station, metric, comparison, threshold, duration, cooldown, since, firing, last_fired): (u64, usize, String, String, f32, u64, u64, Option<usize>, bool, Option<usize>)) -> Option<AlertRuleRow> {
+    Some(AlertRuleRow { id, station, metric: metric.parse().ok()?, comparison: comparison.parse().ok()?, threshold, duration, cooldown, since, firing, last_fired })
+}
+
+pub fn get_alert_rule(id: u64, db: &mut PooledConn) -> Result<AlertRuleRow, Status> {
+    Ok(db.exec_first("SELECT * FROM alert_rules WHERE id = ?", (id,)).or(Err(Status::InternalServerError))?
+        .and_then(alert_rule_row).ok_or(Status::NotFound)?)
+}
+
+pub fn get_alert_rules(station: usize, db: &mut PooledConn) -> Result<Vec<AlertRuleRow>, Status> {
+    Ok(db.exec("SELECT * FROM alert_rules WHERE station = ? ORDER BY id", (station,)).or(Err(Status::InternalServerError))?
+        .into_iter().filter_map(alert_rule_row).collect())
+}
+
+pub fn get_alerts(station: usize, resolved: bool, count: usize, db: &mut PooledConn) -> Result<Vec<AlertRow>, Status> {
+    let query = if resolved {
+        "SELECT * FROM alerts WHERE station = ? ORDER BY id DESC LIMIT ?"
+    } else {
+        "SELECT * FROM alerts WHERE station = ? AND resolved IS NULL ORDER BY id DESC LIMIT ?"
+    };
+    Ok(db.exec(query, (station, count)).or(Err(Status::InternalServerError))?
+        .into_iter().map(|(id, rule, station, value, fired, resolved)| AlertRow { id, rule, station, value, fired, resolved }).collect())
+}
+
+fn webhook_row((id, login, url, events, secret, enabled): (u64, String, String, String, String, bool)) -> WebhookRow {
+    WebhookRow { id, login, url, events: events.split(',').filter(|e| !e.is_empty()).map(str::to_string).collect(), secret, enabled }
+}
+
+pub fn get_webhook(id: u64, db: &mut PooledConn) -> Result<WebhookRow, Status> {
+    Ok(db.exec_first("SELECT * FROM webhooks WHERE id = ?", (id,)).or(Err(Status::InternalServerError))?
+        .map(webhook_row).ok_or(Status::NotFound)?)
+}
+
+pub fn get_webhooks(login: &str, db: &mut PooledConn) -> Result<Vec<WebhookRow>, Status> {
+    Ok(db.exec("SELECT * FROM webhooks WHERE login = ? ORDER BY id", (login,)).or(Err(Status::InternalServerError))?
+        .into_iter().map(webhook_row).collect())
+}
+
+fn webhook_delivery_row((id, webhook, event, kind, time, attempt, status, error, body, next_try): (u64, u64, u64, String, usize, u32, Option<u16>, Option<String>, Option<String>, Option<usize>)) -> WebhookDeliveryRow {
+    WebhookDeliveryRow { id, webhook, event, kind, time, attempt, status, error, body, next_try }
+}
+
+pub fn get_webhook_deliveries(webhook: u64, count: usize, db: &mut PooledConn) -> Result<Vec<WebhookDeliveryRow>, Status> {
+    Ok(db.exec("SELECT * FROM webhook_deliveries WHERE webhook = ? ORDER BY id DESC LIMIT ?", (webhook, count)).or(Err(Status::InternalServerError))?
+        .into_iter().map(webhook_delivery_row).collect())
+}
+
+pub fn get_due_webhook_deliveries(time: usize, db: &mut PooledConn) -> Result<Vec<WebhookDeliveryRow>, Status> {
+    Ok(db.exec("SELECT * FROM webhook_deliveries WHERE next_try <= ? ORDER BY id", (time,)).or(Err(Status::InternalServerError))?
+        .into_iter().map(webhook_delivery_row).collect())
+}
+
+pub fn get_station_statuses(db: &mut PooledConn) -> Result<Vec<StationStatusRow>, Status> {
+    Ok(db.query("SELECT * FROM station_status").or(Err(Status::InternalServerError))?
+        .into_iter().map(|(station, last_seen, offline)| StationStatusRow { station, last_seen, offline }).collect())
+}
+
+pub fn get_tank_prediction(station: usize, db: &mut PooledConn) -> Result<Option<TankPredictionRow>, Status> {
+    Ok(db.exec_first("SELECT * FROM tank_predictions WHERE station = ?", (station,)).or(Err(Status::InternalServerError))?
+        .map(|(station, computed, empty_at)| TankPredictionRow { station, computed, empty_at }))
+}
+
+pub fn set_tank_prediction(prediction: &TankPredictionRow, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("REPLACE INTO tank_predictions (station, computed, empty_at) VALUES (?, ?, ?)", (prediction.station, prediction.computed, prediction.empty_at)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn get_station_status(station: usize, db: &mut PooledConn) -> Result<Option<StationStatusRow>, Status> {
+    Ok(db.exec_first("SELECT * FROM station_status WHERE station = ?", (station,)).or(Err(Status::InternalServerError))?
+        .map(|(station, last_seen, offline)| StationStatusRow { station, last_seen, offline }))
+}
+
+// Faults of a metric or kind this version doesn't know are skipped.
+fn data_fault_row((id, station, time, metric, fault, value): (u64, usize, usize, String, String, f32)) -> Option<DataFaultRow> {
+    Some(DataFaultRow { id, station, time, metric: metric.parse().ok()?, fault: fault.parse().ok()?, value })
+}
+
+// Calibrations of a metric this version doesn't know are skipped.
+fn calibration_row((station, metric, points): (usize, String, String)) -> Option<CalibrationRow> {
+    Some(CalibrationRow { station, metric: metric.parse().ok()?, points: serde_json::from_str(&points).ok()? })
+}
+
+pub fn get_calibrations(station: usize, db: &mut PooledConn) -> Result<Vec<CalibrationRow>, Status> {
+    Ok(db.exec("SELECT * FROM calibrations WHERE station = ?", (station,)).or(Err(Status::InternalServerError))?
+        .into_iter().filter_map(calibration_row).collect())
+}
+
+// Stations without metadata get an empty set.
+pub fn get_station_meta(station: usize, db: &mut PooledConn) -> Result<StationMetaRow, Status> {
+    Ok(db.exec_first("SELECT * FROM station_meta WHERE station = ?", (station,)).or(Err(Status::InternalServerError))?
+        .map(|(station, location, latitude, longitude, timezone, indoor, notes, photo)| StationMetaRow { station, location, latitude, longitude, timezone, indoor, notes, photo })
+        .unwrap_or(StationMetaRow { station, ..Default::default() }))
+}
+
+fn garden_stations(garden: u64, db: &mut PooledConn) -> Result<Vec<usize>, Status> {
+    Ok(db.exec("SELECT station FROM garden_stations WHERE garden = ? ORDER BY station", (garden,)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn get_garden(id: u64, db: &mut PooledConn) -> Result<GardenRow, Status> {
+    let (id, login, name): (u64, String, String) = db.exec_first("SELECT * FROM gardens WHERE id = ?", (id,)).or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+    Ok(GardenRow { id, login, name, stations: garden_stations(id, db)? })
+}
+
+pub fn get_gardens(login: &str, db: &mut PooledConn) -> Result<Vec<GardenRow>, Status> {
+    let gardens: Vec<(u64, String, String)> = db.exec("SELECT * FROM gardens WHERE login = ? ORDER BY id", (login,)).or(Err(Status::InternalServerError))?;
+    gardens.into_iter().map(|(id, login, name)| Ok(GardenRow { id, login, name, stations: garden_stations(id, db)? })).collect()
+}
+
+pub fn get_plants(db: &mut PooledConn) -> Result<Vec<PlantRow>, Status> {
+    Ok(db.query("SELECT * FROM plants ORDER BY name").or(Err(Status::InternalServerError))?
+        .into_iter().map(plant_row).collect())
+}
+
+pub fn get_plant(id: &str, db: &mut PooledConn) -> Result<Option<PlantRow>, Status> {
+    Ok(db.exec_first("SELECT * FROM plants WHERE id = ?", (id,)).or(Err(Status::InternalServerError))?
+        .map(plant_row))
+}
+
+pub fn get_unit_prefs(login: &str, db: &mut PooledConn) -> Result<Option<UnitPrefsRow>, Status> {
+    Ok(db.exec_first("SELECT * FROM unit_prefs WHERE login = ?", (login,)).or(Err(Status::InternalServerError))?
+        .map(|(login, temperature, tank_fill): (String, String, String)| UnitPrefsRow {
+            login,
+            temperature: temperature.parse().unwrap_or(TemperatureUnit::Celsius),
+            tank_fill: tank_fill.parse().unwrap_or(TankUnit::Percent)
+        }))
+}
+
+pub fn get_data_faults(station: usize, count: usize, db: &mut PooledConn) -> Result<Vec<DataFaultRow>, Status> {
+    Ok(db.exec("SELECT * FROM data_faults WHERE station = ? ORDER BY id DESC LIMIT ?", (station, count)).or(Err(Status::InternalServerError))?
+        .into_iter().filter_map(data_fault_row).collect())
+}
+
+pub fn get_data_faults_since(station: usize, since: usize, db: &mut PooledConn) -> Result<Vec<DataFaultRow>, Status> {
+    Ok(db.exec("SELECT * FROM data_faults WHERE station = ? AND time >= ? ORDER BY time", (station, since)).or(Err(Status::InternalServerError))?
+        .into_iter().filter_map(data_fault_row).collect())
+}
+
+pub fn get_outages(station: usize, count: usize, db: &mut PooledConn) -> Result<Vec<OutageRow>, Status> {
+    Ok(db.exec("SELECT * FROM outages WHERE station = ? ORDER BY id DESC LIMIT ?", (station, count)).or(Err(Status::InternalServerError))?
+        .into_iter().map(|(id, station, started, ended)| OutageRow { id, station, started, ended }).collect())
 }
 
 pub fn update_user(user: UserRow, db: &mut PooledConn) -> Result<(), Status> {
-    Ok(db.exec_drop("UPDATE users SET name = ?, pass = ? WHERE login = ?", (&user.name, &user.pass, &user.login)).or(Err(Status::InternalServerError))?)
+    Ok(db.exec_drop("UPDATE users SET name = ?, pass = ?, email = ? WHERE login = ?", (&user.name, &user.pass, &user.email, &user.login)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn update_calibration(calibration: &CalibrationRow, db: &mut PooledConn) -> Result<(), Status> {
+    let points = serde_json::to_string(&calibration.points).or(Err(Status::InternalServerError))?;
+    Ok(db.exec_drop("REPLACE INTO calibrations (station, metric, points) VALUES (?, ?, ?)", (calibration.station, calibration.metric.as_str(), points)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn delete_calibration(station: usize, metric: Metric, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("DELETE FROM calibrations WHERE station = ? AND metric = ?", (station, metric.as_str())).or(Err(Status::InternalServerError))?)
+}
+
+pub fn update_station_meta(meta: &StationMetaRow, db: &mut impl Queryable) -> Result<(), Status> {
+    Ok(db.exec_drop("REPLACE INTO station_meta (station, location, latitude, longitude, timezone, indoor, notes, photo) VALUES (?, ?, ?, ?, ?, ?, ?, ?)", (meta.station, &meta.location, meta.latitude, meta.longitude, &meta.timezone, meta.indoor, &meta.notes, &meta.photo)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn delete_station_meta(station: usize, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("DELETE FROM station_meta WHERE station = ?", (station,)).or(Err(Status::InternalServerError))?)
+}
+
+// Gardens and their stations are written in one transaction, which is rolled
+// back when dropped on an error.
+pub fn add_garden(garden: &GardenRow, db: &mut PooledConn) -> Result<u64, Status> {
+    let mut tx = db.start_transaction(TxOpts::default()).or(Err(Status::InternalServerError))?;
+    tx.exec_drop("INSERT INTO gardens (login, name) VALUES (?, ?)", (&garden.login, &garden.name)).or(Err(Status::InternalServerError))?;
+    let id = tx.last_insert_id().ok_or(Status::InternalServerError)?;
+    tx.exec_batch("INSERT INTO garden_stations (garden, station) VALUES (?, ?)", garden.stations.iter().map(|s| (id, *s))).or(Err(Status::InternalServerError))?;
+    tx.commit().or(Err(Status::InternalServerError))?;
+    Ok(id)
+}
+
+pub fn update_garden(garden: &GardenRow, db: &mut PooledConn) -> Result<(), Status> {
+    let mut tx = db.start_transaction(TxOpts::default()).or(Err(Status::InternalServerError))?;
+    tx.exec_drop("UPDATE gardens SET name = ? WHERE id = ?", (&garden.name, garden.id)).or(Err(Status::InternalServerError))?;
+    tx.exec_drop("DELETE FROM garden_stations WHERE garden = ?", (garden.id,)).or(Err(Status::InternalServerError))?;
+    tx.exec_batch("INSERT INTO garden_stations (garden, station) VALUES (?, ?)", garden.stations.iter().map(|s| (garden.id, *s))).or(Err(Status::InternalServerError))?;
+    Ok(tx.commit().or(Err(Status::InternalServerError))?)
+}
+
+pub fn delete_garden(id: u64, db: &mut PooledConn) -> Result<(), Status> {
+    let mut tx = db.start_transaction(TxOpts::default()).or(Err(Status::InternalServerError))?;
+    tx.exec_drop("DELETE FROM garden_stations WHERE garden = ?", (id,)).or(Err(Status::InternalServerError))?;
+    tx.exec_drop("DELETE FROM gardens WHERE id = ?", (id,)).or(Err(Status::InternalServerError))?;
+    Ok(tx.commit().or(Err(Status::InternalServerError))?)
+}
+
+// Stations leave their gardens when they are released by their owner.
+pub fn remove_garden_station(station: usize, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("DELETE FROM garden_stations WHERE station = ?", (station,)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn update_plant(plant: &PlantRow, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("REPLACE INTO plants (id, name, species, moisture_min, moisture_max, temperature_min, temperature_max, humidity_min, humidity_max, watering) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", (&plant.id, &plant.name, &plant.species, plant.moisture.0, plant.moisture.1, plant.temperature.0, plant.temperature.1, plant.humidity.0, plant.humidity.1, &plant.watering)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn update_unit_prefs(prefs: &UnitPrefsRow, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("REPLACE INTO unit_prefs (login, temperature, tank_fill) VALUES (?, ?, ?)", (&prefs.login, prefs.temperature.as_str(), prefs.tank_fill.as_str())).or(Err(Status::InternalServerError))?)
 }
 
-pub fn update_station(station: StationRow, db: &mut PooledConn) -> Result<(), Status> {
-    Ok(db.exec_drop("UPDATE stations SET name = ?, state = ?, owner = ?, token = ?, conf = ? WHERE id = ?", (&station.name, &station.state, &station.owner, &station.token, &station.conf, station.id)).or(Err(Status::InternalServerError))?)
+pub fn update_notification_prefs(prefs: &NotificationPrefsRow, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("REPLACE INTO notification_prefs (login, events, digest, digest_interval) VALUES (?, ?, ?, ?)", (&prefs.login, prefs.events.join(","), prefs.digest, prefs.digest_interval)).or(Err(Status::InternalServerError))?)
 }
 
-pub fn add_user(login: &str, name: &str, pass: &str, db: &mut PooledConn) -> Result<(), Status> {
-    Ok(db.exec_drop("INSERT INTO users (login, name, pass) VALUES (?, ?, ?)", (login, name, pass)).or(Err(Status::InternalServerError))?)
+pub fn update_station(station: StationRow, db: &mut impl Queryable) -> Result<(), Status> {
+    Ok(db.exec_drop("UPDATE stations SET name = ?, state = ?, owner = ?, token = ?, conf = ?, conf_json = ?, conf_schema = ?, tank_capacity = ?, plant = ? WHERE id = ?", (&station.name, station.unknown_state.as_deref().unwrap_or(station.state.as_str()), &station.owner, &station.token, station.conf.as_ref().map(conf_string), station.conf.as_ref().map(Value::to_string), station.schema.as_ref().map(Value::to_string), station.tank_capacity, &station.plant, station.id)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn add_user(login: &str, name: &str, pass: &str, email: Option<&str>, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("INSERT INTO users (login, name, pass, email) VALUES (?, ?, ?, ?)", (login, name, pass, email)).or(Err(Status::InternalServerError))?)
 }
 
 pub fn add_station(id: usize, name: &str, token: &str, db: &mut PooledConn) -> Result<(), Status> {
-    Ok(db.exec_drop("INSERT INTO stations (id, name, state, token) VALUES (?, ?, ?, ?)", (id, name, "idle", token)).or(Err(Status::InternalServerError))?)
+    Ok(db.exec_drop("INSERT INTO stations (id, name, state, token) VALUES (?, ?, ?, ?)", (id, name, StationState::Idle.as_str(), token)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn add_data(station: usize, data: &DataReq, raw: &DataReq, db: &mut PooledConn) -> Result<DataRow, Status> {
+    let d = DataRow {
+        station,
+        time: now(),
+        moisture: data.moisture,
+        temperature: data.temperature,
+        humidity: data.humidity,
+        tank_fill: data.tank_fill,
+        moisture_raw: raw.moisture,
+        temperature_raw: raw.temperature,
+        humidity_raw: raw.humidity,
+        tank_fill_raw: raw.tank_fill
+    };
+    db.exec_drop("INSERT INTO data (station, time, moisture, temperature, humidity, tank_fill, moisture_raw, temperature_raw, humidity_raw, tank_fill_raw) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", (d.station, d.time, d.moisture, d.temperature, d.humidity, d.tank_fill, d.moisture_raw, d.temperature_raw, d.humidity_raw, d.tank_fill_raw)).or(Err(Status::InternalServerError))?;
+    Ok(d)
+}
+
+pub fn add_history(station: usize, actor: Actor, login: Option<&str>, kind: &str, old_value: Option<&str>, new_value: Option<&str>, source: &str, db: &mut impl Queryable) -> Result<(), Status> {
+    Ok(db.exec_drop("INSERT INTO history (station, time, actor, login, kind, old_value, new_value, source) VALUES (?, ?, ?, ?, ?, ?, ?, ?)", (station, now(), actor.as_str(), login, kind, old_value, new_value, source)).or(Err(Status::InternalServerError))?)
+}
+
+// Revisions are numbered per station, starting at 1.
+// A revision taken concurrently is rejected by the primary key, in which case
+// the next one is tried. The last revision is a locking read so that it is
+// current within a transaction too.
+pub fn add_conf_revision(station: usize, actor: Actor, login: Option<&str>, conf: &Value, db: &mut impl Queryable) -> Result<u64, Status> {
+    for _ in 0..REVISION_ATTEMPTS {
+        let last: Option<u64> = db.exec_first("SELECT MAX(revision) FROM conf_revisions WHERE station = ? FOR UPDATE", (station,)).or(Err(Status::InternalServerError))?.flatten();
+        let revision = last.unwrap_or(0) + 1;
+        match db.exec_drop("INSERT INTO conf_revisions (station, revision, time, actor, login, conf) VALUES (?, ?, ?, ?, ?, ?)", (station, revision, now(), actor.as_str(), login, conf.to_string())) {
+            Ok(_) => return Ok(revision),
+            Err(mysql::Error::MySqlError(e)) if e.code == ER_DUP_ENTRY => continue,
+            Err(_) => return Err(Status::InternalServerError)
+        }
+    }
+    Err(Status::InternalServerError)
+}
+
+// Kept out of `update_station` so a request handler holding an older copy of
+// the row can't overwrite an acknowledgement received in the meantime. Unknown
+// revisions aren't found, ones older than the acknowledged revision conflict.
+pub fn set_conf_acked(station: usize, revision: u64, db: &mut PooledConn) -> Result<(), Status> {
+    get_conf_revision(station, revision, db)?;
+    db.exec_drop("UPDATE stations SET conf_acked = ? WHERE id = ? AND (conf_acked IS NULL OR conf_acked < ?)", (revision, station, revision)).or(Err(Status::InternalServerError))?;
+    if db.affected_rows() > 0 {
+        return Ok(());
+    }
+
+    let acked: Option<u64> = db.exec_first("SELECT conf_acked FROM stations WHERE id = ?", (station,)).or(Err(Status::InternalServerError))?.flatten();
+    match acked {
+        Some(acked) if acked > revision => Err(Status::Conflict),
+        _ => Ok(())
+    }
+}
+
+pub fn add_schedule(schedule: &ScheduleRow, db: &mut PooledConn) -> Result<u64, Status> {
+    db.exec_drop("INSERT INTO schedules (station, times, days, duration, timezone, enabled, created) VALUES (?, ?, ?, ?, ?, ?, ?)", (schedule.station, schedule.times.join(","), schedule.days.join(","), schedule.duration, &schedule.timezone, schedule.enabled, now())).or(Err(Status::InternalServerError))?;
+    Ok(db.last_insert_id())
+}
+
+pub fn update_schedule(schedule: &ScheduleRow, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("UPDATE schedules SET times = ?, days = ?, duration = ?, timezone = ?, enabled = ? WHERE id = ?", (schedule.times.join(","), schedule.days.join(","), schedule.duration, &schedule.timezone, schedule.enabled, schedule.id)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn delete_schedule(id: u64, db: &mut PooledConn) -> Result<(), Status> {
+    db.exec_drop("DELETE FROM schedule_runs WHERE schedule = ?", (id,)).or(Err(Status::InternalServerError))?;
+    Ok(db.exec_drop("DELETE FROM schedules WHERE id = ?", (id,)).or(Err(Status::InternalServerError))?)
+}
+
+// Runs with an end are active until they are ended.
+pub fn add_schedule_run(schedule: u64, station: usize, outcome: &str, ends: Option<usize>, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("INSERT INTO schedule_runs (schedule, station, time, outcome, ends, active) VALUES (?, ?, ?, ?, ?, ?)", (schedule, station, now(), outcome, ends, ends.is_some())).or(Err(Status::InternalServerError))?)
+}
+
+pub fn end_schedule_runs(schedule: u64, station: usize, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("UPDATE schedule_runs SET active = FALSE WHERE schedule = ? AND station = ? AND active", (schedule, station)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn update_control_policy(policy: &ControlPolicyRow, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("REPLACE INTO control_policies (station, enabled, dry_run, moisture_low, moisture_high, max_duration, min_interval, tank_min, last_start, active, simulated) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", (policy.station, policy.enabled, policy.dry_run, policy.moisture_low, policy.moisture_high, policy.max_duration, policy.min_interval, policy.tank_min, policy.last_start, policy.active, policy.simulated)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn delete_control_policy(station: usize, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("DELETE FROM control_policies WHERE station = ?", (station,)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn add_alert_rule(rule: &AlertRuleRow, db: &mut PooledConn) -> Result<u64, Status> {
+    db.exec_drop("INSERT INTO alert_rules (station, metric, comparison, threshold, duration, cooldown, since, firing, last_fired) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)", (rule.station, rule.metric.as_str(), rule.comparison.as_str(), rule.threshold, rule.duration, rule.cooldown, rule.since, rule.firing, rule.last_fired)).or(Err(Status::InternalServerError))?;
+    Ok(db.last_insert_id())
+}
+
+pub fn update_alert_rule(rule: &AlertRuleRow, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("UPDATE alert_rules SET metric = ?, comparison = ?, threshold = ?, duration = ?, cooldown = ?, since = ?, firing = ?, last_fired = ? WHERE id = ?", (rule.metric.as_str(), rule.comparison.as_str(), rule.threshold, rule.duration, rule.cooldown, rule.since, rule.firing, rule.last_fired, rule.id)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn delete_alert_rule(id: u64, db: &mut PooledConn) -> Result<(), Status> {
+    db.exec_drop("DELETE FROM alerts WHERE rule = ?", (id,)).or(Err(Status::InternalServerError))?;
+    Ok(db.exec_drop("DELETE FROM alert_rules WHERE id = ?", (id,)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn add_alert(rule: u64, station: usize, value: f32, time: usize, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("INSERT INTO alerts (rule, station, value, fired) VALUES (?, ?, ?, ?)", (rule, station, value, time)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn resolve_alerts(rule: u64, time: usize, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("UPDATE alerts SET resolved = ? WHERE rule = ? AND resolved IS NULL", (time, rule)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn add_webhook(webhook: &WebhookRow, db: &mut PooledConn) -> Result<u64, Status> {
+    db.exec_drop("INSERT INTO webhooks (login, url, events, secret, enabled) VALUES (?, ?, ?, ?, ?)", (&webhook.login, &webhook.url, webhook.events.join(","), &webhook.secret, webhook.enabled)).or(Err(Status::InternalServerError))?;
+    Ok(db.last_insert_id())
 }
 
-pub fn add_data(station: usize, moisture: Option<f32>, temperature: Option<f32>, humidity: Option<f32>, tank_fill: Option<f32>, db: &mut PooledConn) -> Result<(), Status> {
-    Ok(db.exec_drop("INSERT INTO data (station, time, moisture, temperature, humidity, tank_fill) VALUES (?, ?, ?, ?, ?, ?)", (station, SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(), moisture, temperature, humidity, tank_fill)).or(Err(Status::InternalServerError))?)
+pub fn update_webhook(webhook: &WebhookRow, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("UPDATE webhooks SET url = ?, events = ?, secret = ?, enabled = ? WHERE id = ?", (&webhook.url, webhook.events.join(","), &webhook.secret, webhook.enabled, webhook.id)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn delete_webhook(id: u64, db: &mut PooledConn) -> Result<(), Status> {
+    db.exec_drop("DELETE FROM webhook_deliveries WHERE webhook = ?", (id,)).or(Err(Status::InternalServerError))?;
+    Ok(db.exec_drop("DELETE FROM webhooks WHERE id = ?", (id,)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn add_webhook_delivery(webhook: u64, event: u64, kind: &str, attempt: u32, status: Option<u16>, error: Option<&str>, db: &mut PooledConn) -> Result<u64, Status> {
+    db.exec_drop("INSERT INTO webhook_deliveries (webhook, event, kind, time, attempt, status, error) VALUES (?, ?, ?, ?, ?, ?, ?)", (webhook, event, kind, now(), attempt, status, error)).or(Err(Status::InternalServerError))?;
+    Ok(db.last_insert_id())
+}
+
+// Marks a delivery to be sent (again) at `next_try`. A delivery with attempt 0
+// is queued but hasn't been sent yet.
+pub fn retry_webhook_delivery(id: u64, body: &str, next_try: usize, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("UPDATE webhook_deliveries SET body = ?, next_try = ? WHERE id = ?", (body, next_try, id)).or(Err(Status::InternalServerError))?)
+}
+
+// Returns whether the delivery was claimed, so that only one of several
+// instances sends it. Queued deliveries that were never attempted are removed,
+// failed attempts stay in the log.
+pub fn take_webhook_delivery(delivery: &WebhookDeliveryRow, db: &mut PooledConn) -> Result<bool, Status> {
+    if delivery.attempt == 0 {
+        db.exec_drop("DELETE FROM webhook_deliveries WHERE id = ?", (delivery.id,)).or(Err(Status::InternalServerError))?;
+    } else {
+        db.exec_drop("UPDATE webhook_deliveries SET body = NULL, next_try = NULL WHERE id = ? AND next_try IS NOT NULL", (delivery.id,)).or(Err(Status::InternalServerError))?;
+    }
+    Ok(db.affected_rows() > 0)
+}
+
+pub fn touch_station(station: usize, time: usize, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("INSERT INTO station_status (station, last_seen, offline) VALUES (?, ?, FALSE) ON DUPLICATE KEY UPDATE last_seen = GREATEST(last_seen, VALUES(last_seen))", (station, time)).or(Err(Status::InternalServerError))?)
+}
+
+// Returns whether the flag changed, so that only one of several instances
+// watching the same database acts on it.
+pub fn set_station_offline(station: usize, offline: bool, db: &mut PooledConn) -> Result<bool, Status> {
+    db.exec_drop("UPDATE station_status SET offline = ? WHERE station = ? AND offline = ?", (offline, station, !offline)).or(Err(Status::InternalServerError))?;
+    Ok(db.affected_rows() > 0)
+}
+
+pub fn add_data_fault(station: usize, time: usize, metric: Metric, fault: Fault, value: f32, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("INSERT INTO data_faults (station, time, metric, fault, value) VALUES (?, ?, ?, ?, ?)", (station, time, metric.as_str(), fault.as_str(), value)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn add_outage(station: usize, started: usize, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("INSERT INTO outages (station, started) VALUES (?, ?)", (station, started)).or(Err(Status::InternalServerError))?)
+}
+
+// Returns the start of the outage that was ended, if there was one.
+pub fn end_outage(station: usize, ended: usize, db: &mut PooledConn) -> Result<Option<usize>, Status> {
+    let started: Option<usize> = db.exec_first("SELECT started FROM outages WHERE station = ? AND ended IS NULL ORDER BY id DESC", (station,)).or(Err(Status::InternalServerError))?;
+    db.exec_drop("UPDATE outages SET ended = ? WHERE station = ? AND ended IS NULL", (ended, station)).or(Err(Status::InternalServerError))?;
+    Ok(started)
 }
 
 pub fn delete_user(user: UserRow, db: &mut PooledConn) -> Result<(), Status> {
+    db.exec_drop("DELETE FROM notification_prefs WHERE login = ?", (&user.login,)).or(Err(Status::InternalServerError))?;
+    db.exec_drop("DELETE FROM unit_prefs WHERE login = ?", (&user.login,)).or(Err(Status::InternalServerError))?;
+    db.exec_drop("DELETE FROM garden_stations WHERE garden IN (SELECT id FROM gardens WHERE login = ?)", (&user.login,)).or(Err(Status::InternalServerError))?;
+    db.exec_drop("DELETE FROM gardens WHERE login = ?", (&user.login,)).or(Err(Status::InternalServerError))?;
+    db.exec_drop("DELETE FROM webhook_deliveries WHERE webhook IN (SELECT id FROM webhooks WHERE login = ?)", (&user.login,)).or(Err(Status::InternalServerError))?;
+    db.exec_drop("DELETE FROM webhooks WHERE login = ?", (&user.login,)).or(Err(Status::InternalServerError))?;
     Ok(db.exec_drop("DELETE FROM users WHERE login = ?", (&user.login,)).or(Err(Status::InternalServerError))?)
 }
 
+pub fn get_presence(station: usize, db: &mut PooledConn) -> Result<Option<String>, Status> {
+    Ok(db.exec_first("SELECT instance FROM ws_presence WHERE station = ?", (station,)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn set_presence(station: usize, instance: &str, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("REPLACE INTO ws_presence (station, instance, seen) VALUES (?, ?, ?)", (station, instance, now())).or(Err(Status::InternalServerError))?)
+}
+
+// Marks the stations of a running instance as still connected.
+pub fn touch_presence(instance: &str, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("UPDATE ws_presence SET seen = ? WHERE instance = ?", (now(), instance)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn delete_presence(station: usize, instance: &str, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("DELETE FROM ws_presence WHERE station = ? AND instance = ?", (station, instance)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn delete_instance_presence(instance: &str, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("DELETE FROM ws_presence WHERE instance = ?", (instance,)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn delete_presence_before(age: Duration, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("DELETE FROM ws_presence WHERE seen IS NULL OR seen < ?", (now().saturating_sub(age.as_secs() as usize),)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn add_bus_message(instance: &str, request: &str, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("INSERT INTO ws_bus (instance, time, request) VALUES (?, ?, ?)", (instance, now(), request)).or(Err(Status::InternalServerError))?)
+}
+
+pub fn take_bus_messages(instance: &str, db: &mut PooledConn) -> Result<Vec<String>, Status> {
+    let msgs: Vec<(u64, String)> = db.exec("SELECT seq, request FROM ws_bus WHERE instance = ? ORDER BY seq", (instance,)).or(Err(Status::InternalServerError))?;
+    if let Some((seq, _)) = msgs.last() {
+        db.exec_drop("DELETE FROM ws_bus WHERE instance = ? AND seq <= ?", (instance, seq)).or(Err(Status::InternalServerError))?;
+    }
+    Ok(msgs.into_iter().map(|(_, request)| request).collect())
+}
+
+pub fn delete_bus_messages_before(age: Duration, db: &mut PooledConn) -> Result<(), Status> {
+    Ok(db.exec_drop("DELETE FROM ws_bus WHERE time < ?", (now().saturating_sub(age.as_secs() as usize),)).or(Err(Status::InternalServerError))?)
+}
+
 #[derive(Debug, Deserialize)]
 pub struct StationsReq {
     pub id: usize,
@@ -114,7 +954,26 @@ pub struct StationsReq {
 #[derive(Debug, Deserialize)]
 pub struct StationReq {
     pub name: Option<String>,
-    pub conf: Option<String>
+    pub conf: Option<String>,
+    pub conf_json: Option<Value>,
+    pub schema: Option<Value>,
+    pub tank_capacity: Option<f32>,
+    pub plant: Option<String>,
+    pub location: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub latitude: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub longitude: Option<Option<f64>>,
+    pub timezone: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub indoor: Option<Option<bool>>,
+    pub notes: Option<String>,
+    pub photo: Option<String>
+}
+
+// Tells an explicit `null`, which clears a field, apart from a missing one.
+fn nullable<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Option<Option<T>>, D::Error> {
+    Option::deserialize(deserializer).map(Some)
 }
 
 #[derive(Debug, Deserialize)]
@@ -127,20 +986,29 @@ pub struct DataReq {
 
 #[derive(Debug, Deserialize)]
 pub struct StateReq {
-    pub state: String
+    pub state: StationState
 }
 
 #[derive(Debug, Deserialize)]
 pub struct UsersReq {
     pub login: String,
     pub name: String,
-    pub pass: String
+    pub pass: String,
+    pub email: Option<String>
 }
 
 #[derive(Debug, Deserialize)]
 pub struct UserReq {
     pub name: String,
-    pub pass: String
+    pub pass: String,
+    pub email: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationsReq {
+    pub events: Option<Vec<String>>,
+    pub digest: Option<bool>,
+    pub digest_interval: Option<u64>
 }
 
 #[derive(Debug, Deserialize)]
@@ -160,17 +1028,62 @@ pub struct StationsResp {
 pub struct StationResp {
     pub name: String,
     pub owner: Option<String>,
-    pub conf: String
+    pub conf: String,
+    pub conf_json: Value,
+    pub schema: Option<Value>,
+    pub tank_capacity: Option<f32>,
+    pub plant: Option<String>,
+    pub location: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub timezone: Option<String>,
+    pub indoor: Option<bool>,
+    pub notes: Option<String>,
+    pub photo: Option<String>
 }
 
 #[derive(Debug, Serialize)]
 pub struct DataResp {
+    pub units: UnitsResp,
     pub data: Vec<DataElement>
 }
 
 #[derive(Debug, Serialize)]
 pub struct DataElement {
     pub time: usize,
+    pub moisture: Option<f32>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub tank_fill: Option<f32>,
+    pub raw: RawElement
+}
+
+#[derive(Debug, Serialize)]
+pub struct DailyResp {
+    pub timezone: String,
+    pub units: UnitsResp,
+    pub days: Vec<DailyElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct DailyElement {
+    pub date: String,
+    pub count: usize,
+    pub moisture: Option<StatsElement>,
+    pub temperature: Option<StatsElement>,
+    pub humidity: Option<StatsElement>,
+    pub tank_fill: Option<StatsElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatsElement {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32
+}
+
+#[derive(Debug, Serialize)]
+pub struct RawElement {
     pub moisture: Option<f32>,
     pub temperature: Option<f32>,
     pub humidity: Option<f32>,
@@ -179,12 +1092,398 @@ pub struct DataElement {
 
 #[derive(Debug, Serialize)]
 pub struct StateResp {
-    pub state: String
+    pub state: StationState
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryResp {
+    pub history: Vec<HistoryElement>,
+    pub next: Option<u64>
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryElement {
+    pub id: u64,
+    pub time: usize,
+    pub actor: String,
+    pub login: Option<String>,
+    pub kind: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub source: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RollbackReq {
+    pub revision: u64
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfRevisionsResp {
+    pub revisions: Vec<ConfRevisionElement>,
+    pub acknowledged: Option<u64>
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfRevisionElement {
+    pub revision: u64,
+    pub time: usize,
+    pub actor: String,
+    pub login: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfRevisionResp {
+    pub revision: u64,
+    pub time: usize,
+    pub actor: String,
+    pub login: Option<String>,
+    pub conf: Value
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfDiffResp {
+    pub from: u64,
+    pub to: u64,
+    pub changes: Vec<ConfChange>
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfChange {
+    pub path: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduleReq {
+    pub times: Vec<String>,
+    pub days: Option<Vec<String>>,
+    pub duration: u64,
+    pub timezone: Option<String>,
+    pub enabled: Option<bool>
+}
+
+#[derive(Debug, Serialize)]
+pub struct SchedulesResp {
+    pub schedules: Vec<ScheduleElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScheduleElement {
+    pub id: u64,
+    pub times: Vec<String>,
+    pub days: Vec<String>,
+    pub duration: u64,
+    pub timezone: Option<String>,
+    pub enabled: bool
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScheduleCreatedResp {
+    pub id: u64
+}
+
+#[derive(Debug, Serialize)]
+pub struct SchedulePreviewResp {
+    pub runs: Vec<SchedulePreviewElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct SchedulePreviewElement {
+    pub start: usize,
+    pub end: usize
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScheduleRunsResp {
+    pub runs: Vec<ScheduleRunElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScheduleRunElement {
+    pub time: usize,
+    pub outcome: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ControlReq {
+    pub enabled: Option<bool>,
+    pub dry_run: Option<bool>,
+    pub moisture_low: Option<f32>,
+    pub moisture_high: Option<f32>,
+    pub max_duration: u64,
+    pub min_interval: u64,
+    pub tank_min: Option<f32>
+}
+
+#[derive(Debug, Serialize)]
+pub struct ControlResp {
+    pub enabled: bool,
+    pub dry_run: bool,
+    pub moisture_low: f32,
+    pub moisture_high: f32,
+    pub max_duration: u64,
+    pub min_interval: u64,
+    pub tank_min: Option<f32>,
+    pub last_start: Option<usize>,
+    pub active: bool,
+    pub simulated: bool
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AlertRuleReq {
+    pub metric: Metric,
+    pub comparison: Comparison,
+    pub threshold: Option<f32>,
+    pub duration: Option<u64>,
+    pub cooldown: Option<u64>
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlertRulesResp {
+    pub rules: Vec<AlertRuleElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlertRuleElement {
+    pub id: u64,
+    pub metric: Metric,
+    pub comparison: Comparison,
+    pub threshold: f32,
+    pub duration: u64,
+    pub cooldown: u64,
+    pub firing: bool
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlertRuleCreatedResp {
+    pub id: u64
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlertsResp {
+    pub alerts: Vec<AlertElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlertElement {
+    pub id: u64,
+    pub rule: u64,
+    pub value: f32,
+    pub fired: usize,
+    pub resolved: Option<usize>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookReq {
+    pub url: String,
+    pub events: Option<Vec<String>>,
+    pub secret: Option<String>,
+    pub enabled: Option<bool>
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhooksResp {
+    pub webhooks: Vec<WebhookElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookElement {
+    pub id: u64,
+    pub url: String,
+    pub events: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub enabled: bool
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookDeliveriesResp {
+    pub deliveries: Vec<WebhookDeliveryElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookDeliveryElement {
+    pub event: u64,
+    pub kind: String,
+    pub time: usize,
+    pub attempt: u32,
+    pub status: Option<u16>,
+    pub error: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutagesResp {
+    pub last_seen: Option<usize>,
+    pub offline: bool,
+    pub outages: Vec<OutageElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutageElement {
+    pub started: usize,
+    pub ended: Option<usize>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CalibrationReq {
+    pub points: Vec<(f32, f32)>
+}
+
+#[derive(Debug, Serialize)]
+pub struct CalibrationsResp {
+    pub calibrations: Vec<CalibrationElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct CalibrationElement {
+    pub metric: Metric,
+    pub points: Vec<(f32, f32)>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GardenReq {
+    pub name: String,
+    pub stations: Vec<usize>
+}
+
+#[derive(Debug, Serialize)]
+pub struct GardensResp {
+    pub gardens: Vec<GardenElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct GardenElement {
+    pub id: u64,
+    pub name: String,
+    pub stations: Vec<usize>
+}
+
+#[derive(Debug, Serialize)]
+pub struct GardenCreatedResp {
+    pub id: u64
+}
+
+#[derive(Debug, Serialize)]
+pub struct GardenDataResp {
+    pub stations: Vec<GardenDataElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct GardenDataElement {
+    pub station: usize,
+    pub name: String,
+    pub state: StationState,
+    pub units: UnitsResp,
+    pub data: Option<DataElement>
+}
+
+// The outcome of a bulk action for each station, as an HTTP status code.
+#[derive(Debug, Serialize)]
+pub struct BulkResp {
+    pub stations: Vec<BulkElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkElement {
+    pub station: usize,
+    pub status: u16
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlantsResp {
+    pub plants: Vec<PlantElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlantElement {
+    pub id: String,
+    pub name: String,
+    pub species: String,
+    pub moisture: (f32, f32),
+    pub temperature: (f32, f32),
+    pub humidity: (f32, f32),
+    pub watering: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnitsReq {
+    pub temperature: Option<TemperatureUnit>,
+    pub tank_fill: Option<TankUnit>
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnitsResp {
+    pub temperature: TemperatureUnit,
+    pub tank_fill: TankUnit
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorsResp {
+    pub errors: Vec<FieldError>
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthResp {
+    pub healthy: bool,
+    pub sensors: Vec<SensorHealthElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct SensorHealthElement {
+    pub metric: Metric,
+    pub status: String,
+    pub value: Option<f32>,
+    pub last_reading: Option<usize>,
+    pub faults: usize,
+    pub last_fault: Option<usize>
+}
+
+#[derive(Debug, Serialize)]
+pub struct DataFaultsResp {
+    pub faults: Vec<DataFaultElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct DataFaultElement {
+    pub time: usize,
+    pub metric: Metric,
+    pub fault: Fault,
+    pub value: f32
+}
+
+#[derive(Debug, Serialize)]
+pub struct TankResp {
+    pub fill: Option<f32>,
+    pub rate: Option<f32>,
+    pub empty_at: Option<usize>,
+    pub last_refill: Option<usize>
+}
+
+#[derive(Debug, Serialize)]
+pub struct RefillsResp {
+    pub refills: Vec<RefillElement>
+}
+
+#[derive(Debug, Serialize)]
+pub struct RefillElement {
+    pub time: usize,
+    pub from: f32,
+    pub to: f32
 }
 
 #[derive(Debug, Serialize)]
 pub struct UserResp {
-    pub name: String
+    pub name: String,
+    pub email: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+pub struct NotificationsResp {
+    pub events: Vec<String>,
+    pub digest: bool,
+    pub digest_interval: u64
 }
 
 #[derive(Debug, Serialize)]
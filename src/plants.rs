@@ -0,0 +1,117 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Plant catalogue.
+//!
+//! The bundled dataset in `res/plants.json` is imported on startup, followed
+//! by the file named by the `plants_file` key in `conf.toml` if there is one.
+//! Both are JSON arrays of profiles, entries with the id of an existing
+//! profile replace it.
+
+use std::collections::HashMap;
+use std::fs;
+
+use mysql::PooledConn;
+use serde::Deserialize;
+
+use crate::model::*;
+use crate::alerts::Metric;
+
+// Ids are stored as VARCHAR(64).
+pub const ID_MAX: usize = 64;
+
+const BUNDLED: &str = include_str!("../res/plants.json");
+
+#[derive(Debug, Deserialize)]
+struct Profile {
+    id: String,
+    name: String,
+    species: String,
+    moisture: (f32, f32),
+    temperature: (f32, f32),
+    humidity: (f32, f32),
+    watering: String
+}
+
+// Ranges must be finite and not end before they start.
+fn check(p: &Profile) -> Result<(), &'static str> {
+    if p.id.is_empty() || p.id.len() > ID_MAX {
+        return Err("the id must be 1 to 64 bytes long");
+    }
+    for (min, max) in [p.moisture, p.temperature, p.humidity].iter() {
+        if !min.is_finite() || !max.is_finite() || min > max {
+            return Err("a range is invalid");
+        }
+    }
+    Ok(())
+}
+
+pub fn import(conf: &HashMap<String, String>, db: &mut PooledConn) {
+    import_str("bundled", BUNDLED, db);
+
+    if let Some(path) = conf.get("plants_file") {
+        match fs::read_to_string(path) {
+            Ok(data) => import_str(path, &data, db),
+            Err(e) => println!("[PLANTS]: Failed to read {} ({})", path, e)
+        }
+    }
+}
+
+fn import_str(source: &str, data: &str, db: &mut PooledConn) {
+    let profiles: Vec<Profile> = match serde_json::from_str(data) {
+        Ok(profiles) => profiles,
+        Err(e) => {
+            println!("[PLANTS]: Failed to parse the {} dataset ({})", source, e);
+            return;
+        }
+    };
+
+    let mut count = 0;
+    for p in profiles.into_iter() {
+        if let Err(e) = check(&p) {
+            println!("[PLANTS]: Skipped {} from the {} dataset ({})", p.id, source, e);
+            continue;
+        }
+
+        let plant = PlantRow {
+            id: p.id,
+            name: p.name,
+            species: p.species,
+            moisture: p.moisture,
+            temperature: p.temperature,
+            humidity: p.humidity,
+            watering: p.watering
+        };
+        if update_plant(&plant, db).is_ok() {
+            count += 1;
+        } else {
+            println!("[PLANTS]: Failed to import {}", plant.id);
+        }
+    }
+    println!("[PLANTS]: Imported {} profiles from the {} dataset", count, source);
+}
+
+// The range a metric should stay in for the plant to thrive.
+pub fn range(plant: &PlantRow, metric: Metric) -> Option<(f32, f32)> {
+    match metric {
+        Metric::Moisture => Some(plant.moisture),
+        Metric::Temperature => Some(plant.temperature),
+        Metric::Humidity => Some(plant.humidity),
+        _ => None
+    }
+}
@@ -0,0 +1,209 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::sync::{Mutex, Arc};
+use std::time::Duration;
+use std::thread;
+
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use mysql::{Pool, PooledConn};
+use rocket::http::Status;
+
+use crate::model::*;
+use crate::bus::Bus;
+use crate::control::set_state;
+use crate::events::*;
+use crate::state::{Actor, StationState};
+use crate::validation::FieldError;
+
+const TICK: Duration = Duration::new(30, 0);
+pub const DEFAULT_TIMEZONE: &str = "UTC";
+
+// A schedule parsed from its row. Times are local to the schedule's timezone,
+// or the station's if the schedule doesn't have one. An empty list of days
+// means every day.
+pub struct Plan {
+    times: Vec<NaiveTime>,
+    days: Vec<Weekday>,
+    duration: chrono::Duration,
+    tz: Tz
+}
+
+impl Plan {
+    pub fn from_row(schedule: &ScheduleRow, station_tz: Option<&str>) -> Result<Self, FieldError> {
+        let mut times = schedule.times.iter()
+            .map(|t| NaiveTime::parse_from_str(t, "%H:%M"))
+            .collect::<Result<Vec<_>, _>>().or(Err(FieldError::new("times", "must be times of day (HH:MM)")))?;
+        times.sort();
+        let days = schedule.days.iter()
+            .map(|d| d.parse())
+            .collect::<Result<Vec<Weekday>, _>>().or(Err(FieldError::new("days", "must be days of the week")))?;
+        let tz = match schedule.timezone.as_str() {
+            "" => station_tz.unwrap_or(DEFAULT_TIMEZONE),
+            tz => tz
+        }.parse().or(Err(FieldError::new("timezone", "must be an IANA time zone")))?;
+
+        if times.is_empty() {
+            return Err(FieldError::new("times", "must not be empty"));
+        }
+        if schedule.duration == 0 {
+            return Err(FieldError::new("duration", "must be positive"));
+        }
+
+        Ok(Self {
+            times,
+            days,
+            duration: chrono::Duration::seconds(schedule.duration as i64),
+            tz
+        })
+    }
+
+    // Start and end of the next `count` runs starting after `after`. Local
+    // times skipped by a DST change don't run, repeated ones run once.
+    pub fn next_runs(&self, after: DateTime<Utc>, count: usize) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        let mut runs = Vec::new();
+        let mut date = after.with_timezone(&self.tz).date().naive_local();
+
+        for _ in 0..(count + 1) * 7 {
+            if self.days.is_empty() || self.days.contains(&date.weekday()) {
+                for time in self.times.iter() {
+                    if let Some(start) = self.tz.from_local_datetime(&date.and_time(*time)).earliest() {
+                        let start = start.with_timezone(&Utc);
+                        if start > after {
+                            runs.push((start, start + self.duration));
+                            if runs.len() == count {
+                                return runs;
+                            }
+                        }
+                    }
+                }
+            }
+            date = date.succ();
+        }
+
+        runs
+    }
+}
+
+// Every instance evaluates all schedules, so in deployments with several
+// instances sharing a database all but one should set `scheduler = "off"`.
+pub fn run(db_conn: Arc<Mutex<Pool>>, conf: HashMap<String, String>, reqs: Arc<Mutex<Box<dyn Bus>>>, events: Arc<Mutex<EventLog>>) {
+    if conf.get("scheduler").map(String::as_str) == Some("off") {
+        return;
+    }
+
+    let mut last = Utc::now();
+
+    // Runs that were in progress when the process stopped are resumed, or
+    // stopped on the first tick if they should have ended in the meantime.
+    // Starting without them would leave those stations watering, so loading
+    // them is retried until the database is available.
+    let mut active: Vec<(u64, usize, DateTime<Utc>)> = loop {
+        let db = db_conn.lock().unwrap().get_conn();
+        let runs = match db {
+            Ok(mut db) => get_active_schedule_runs(&mut db),
+            Err(_) => Err(Status::InternalServerError)
+        };
+        match runs {
+            Ok(runs) => break runs.into_iter()
+                .filter_map(|r| Some((r.schedule, r.station, Utc.timestamp(r.ends? as i64, 0))))
+                .collect(),
+            Err(_) => {
+                println!("[SCHED]: Failed to load active runs");
+                thread::sleep(TICK);
+            }
+        }
+    };
+    for (schedule, station, end) in active.iter() {
+        println!("[SCHED]: Resuming schedule {} for station {} until {}", schedule, station, end);
+    }
+
+    loop {
+        let now = Utc::now();
+        let mut db = match db_conn.lock().unwrap().get_conn() {
+            Ok(db) => db,
+            Err(_) => {
+                println!("[SCHED]: Failed to connect to the database");
+                thread::sleep(TICK);
+                continue;
+            }
+        };
+
+        // Finished runs are stopped first so back to back runs keep watering.
+        for (schedule, station, _) in active.iter().filter(|(_, _, end)| *end <= now) {
+            stop_run(*schedule, *station, &mut db, &reqs, &events);
+        }
+        active.retain(|(_, _, end)| *end > now);
+
+        match get_enabled_schedules(&mut db) {
+            Ok(schedules) => for schedule in schedules.iter() {
+                let station_tz = get_station_meta(schedule.station, &mut db).ok().and_then(|m| m.timezone);
+                let plan = match Plan::from_row(schedule, station_tz.as_deref()) {
+                    Ok(plan) => plan,
+                    Err(_) => continue
+                };
+                if let Some((_, end)) = plan.next_runs(last, 1).into_iter().find(|(start, _)| *start <= now) {
+                    if start_run(schedule.id, schedule.station, end, &mut db, &reqs, &events) {
+                        active.push((schedule.id, schedule.station, end));
+                    }
+                }
+            },
+            Err(_) => println!("[SCHED]: Failed to load schedules")
+        }
+
+        last = now;
+        drop(db);
+        thread::sleep(TICK);
+    }
+}
+
+// The end of a started run is stored with it so it can be stopped after a restart.
+fn start_run(schedule: u64, station: usize, end: DateTime<Utc>, db: &mut PooledConn, reqs: &Arc<Mutex<Box<dyn Bus>>>, events: &Arc<Mutex<EventLog>>) -> bool {
+    let (outcome, started) = match get_station(station, db) {
+        Ok(s) if s.state.can_transition(StationState::Watering, Actor::System) && s.state != StationState::Watering => {
+            match set_state(s, StationState::Watering, &format!("schedule {}", schedule), db, reqs, events) {
+                Ok(_) => ("started".to_string(), true),
+                Err(_) => ("failed".to_string(), false)
+            }
+        }
+        Ok(s) => (format!("skipped ({})", s.state), false),
+        Err(_) => ("failed".to_string(), false)
+    };
+
+    println!("[SCHED]: Schedule {} for station {}: {}", schedule, station, outcome);
+    add_schedule_run(schedule, station, &outcome, Some(end.timestamp() as usize).filter(|_| started), db).ok();
+    started
+}
+
+// Only stops watering that is still going, a user may have taken over since.
+fn stop_run(schedule: u64, station: usize, db: &mut PooledConn, reqs: &Arc<Mutex<Box<dyn Bus>>>, events: &Arc<Mutex<EventLog>>) {
+    let outcome = match get_station(station, db) {
+        Ok(s) if s.state == StationState::Watering => match set_state(s, StationState::Idle, &format!("schedule {}", schedule), db, reqs, events) {
+            Ok(_) => "stopped".to_string(),
+            Err(_) => "failed to stop".to_string()
+        },
+        Ok(s) => format!("interrupted ({})", s.state),
+        Err(_) => "failed to stop".to_string()
+    };
+
+    println!("[SCHED]: Schedule {} for station {}: {}", schedule, station, outcome);
+    end_schedule_runs(schedule, station, db).ok();
+    add_schedule_run(schedule, station, &outcome, None, db).ok();
+}
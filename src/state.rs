@@ -0,0 +1,107 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StationState {
+    Idle,
+    Watering,
+    Manual,
+    Error,
+    Maintenance
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Actor {
+    User,
+    Station,
+    System
+}
+
+use StationState::*;
+use Actor::*;
+
+// (from, to, actors allowed to make the transition). Staying in the same
+// state is always allowed.
+const TRANSITIONS: &[(StationState, StationState, &[Actor])] = &[
+    (Idle, Watering, &[User, Station, System]),
+    (Watering, Idle, &[User, Station, System]),
+    (Idle, Manual, &[User]),
+    (Manual, Idle, &[User]),
+    (Manual, Watering, &[User]),
+    (Watering, Manual, &[User]),
+    (Idle, Error, &[Station, System]),
+    (Watering, Error, &[Station, System]),
+    (Manual, Error, &[Station, System]),
+    (Error, Idle, &[User, Station]),
+    (Idle, Maintenance, &[User]),
+    (Error, Maintenance, &[User]),
+    (Maintenance, Idle, &[User])
+];
+
+impl StationState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Idle => "idle",
+            Watering => "watering",
+            Manual => "manual",
+            Error => "error",
+            Maintenance => "maintenance"
+        }
+    }
+
+    pub fn can_transition(&self, to: StationState, actor: Actor) -> bool {
+        *self == to || TRANSITIONS.iter().any(|(f, t, actors)| f == self && *t == to && actors.contains(&actor))
+    }
+}
+
+impl Actor {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            User => "user",
+            Station => "station",
+            System => "system"
+        }
+    }
+}
+
+impl FromStr for StationState {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "idle" => Ok(Idle),
+            "watering" => Ok(Watering),
+            "manual" => Ok(Manual),
+            "error" => Ok(Error),
+            "maintenance" => Ok(Maintenance),
+            _ => Err(())
+        }
+    }
+}
+
+impl fmt::Display for StationState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
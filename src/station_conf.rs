@@ -0,0 +1,103 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use jsonschema::JSONSchema;
+use rocket::http::Status;
+use serde_json::{Map, Value};
+
+use crate::model::ConfChange;
+use crate::validation::FieldError;
+
+// The `conf` string of the API and the legacy protocol, which used to hold a
+// JSON encoded configuration. String configurations are passed on as they are.
+pub fn conf_string(conf: &Value) -> String {
+    match conf {
+        Value::String(s) => s.clone(),
+        conf => conf.to_string()
+    }
+}
+
+// Configurations stored before they were structured are JSON encoded strings,
+// or plain strings if they don't parse.
+pub fn parse_legacy_conf(conf: String) -> Value {
+    serde_json::from_str(&conf).unwrap_or(Value::String(conf))
+}
+
+pub fn check_schema(schema: &Value) -> Result<(), Status> {
+    JSONSchema::compile(schema).map(|_| ()).or(Err(Status::UnprocessableEntity))
+}
+
+// The errors are reported for `conf` followed by the JSON pointer of the
+// offending value, an empty list means the configuration is valid.
+pub fn validate(schema: Option<&Value>, conf: &Value) -> Result<Vec<FieldError>, Status> {
+    if let Some(schema) = schema {
+        let schema = JSONSchema::compile(schema).or(Err(Status::InternalServerError))?;
+        if let Err(errors) = schema.validate(conf) {
+            return Ok(errors.map(|e| FieldError::new(&format!("conf{}", e.instance_path), &e.to_string())).collect());
+        }
+    }
+    Ok(Vec::new())
+}
+
+// RFC 7396
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    if let Value::Object(patch) = patch {
+        if !target.is_object() {
+            *target = Value::Object(Map::new());
+        }
+
+        if let Value::Object(target) = target {
+            for (key, value) in patch.iter() {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+                }
+            }
+        }
+    } else {
+        *target = patch.clone();
+    }
+}
+
+// Changes between two configurations, addressed by JSON pointer. Objects are
+// compared key by key, everything else (including arrays) as a whole.
+pub fn diff(old: &Value, new: &Value) -> Vec<ConfChange> {
+    let mut changes = Vec::new();
+    diff_at(String::new(), Some(old), Some(new), &mut changes);
+    changes
+}
+
+fn diff_at(path: String, old: Option<&Value>, new: Option<&Value>, changes: &mut Vec<ConfChange>) {
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            let mut keys: Vec<&String> = old.keys().chain(new.keys().filter(|k| !old.contains_key(*k))).collect();
+            keys.sort();
+            for key in keys {
+                let path = format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"));
+                diff_at(path, old.get(key), new.get(key), changes);
+            }
+        }
+        (old, new) if old != new => changes.push(ConfChange {
+            path,
+            old: old.cloned(),
+            new: new.cloned()
+        }),
+        _ => ()
+    }
+}
@@ -0,0 +1,150 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::model::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TankUnit {
+    Percent,
+    Litres
+}
+
+impl TemperatureUnit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "celsius",
+            TemperatureUnit::Fahrenheit => "fahrenheit"
+        }
+    }
+
+    pub fn convert(&self, celsius: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit => celsius * 1.8 + 32.0
+        }
+    }
+}
+
+impl FromStr for TemperatureUnit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "celsius" => Ok(TemperatureUnit::Celsius),
+            "fahrenheit" => Ok(TemperatureUnit::Fahrenheit),
+            _ => Err(())
+        }
+    }
+}
+
+impl TankUnit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TankUnit::Percent => "percent",
+            TankUnit::Litres => "litres"
+        }
+    }
+}
+
+impl FromStr for TankUnit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "percent" => Ok(TankUnit::Percent),
+            "litres" => Ok(TankUnit::Litres),
+            _ => Err(())
+        }
+    }
+}
+
+// The units readings of a station are presented in. Readings are stored in
+// °C and percent, litres are only available for stations with a known tank
+// capacity and fall back to percent otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct Units {
+    pub temperature: TemperatureUnit,
+    pub tank_fill: TankUnit,
+    tank_capacity: Option<f32>
+}
+
+impl Units {
+    pub fn new(prefs: Option<&UnitPrefsRow>, tank_capacity: Option<f32>) -> Self {
+        let tank_fill = match prefs.map(|p| p.tank_fill) {
+            Some(TankUnit::Litres) if tank_capacity.is_some() => TankUnit::Litres,
+            _ => TankUnit::Percent
+        };
+
+        Self {
+            temperature: prefs.map(|p| p.temperature).unwrap_or(TemperatureUnit::Celsius),
+            tank_fill,
+            tank_capacity
+        }
+    }
+
+    pub fn temperature(&self, celsius: f32) -> f32 {
+        self.temperature.convert(celsius)
+    }
+
+    pub fn tank_fill(&self, percent: f32) -> f32 {
+        match self.tank_fill {
+            TankUnit::Litres => percent / 100.0 * self.tank_capacity.unwrap_or(0.0),
+            TankUnit::Percent => percent
+        }
+    }
+
+    pub fn element(&self, d: &DataRow) -> DataElement {
+        DataElement {
+            time: d.time,
+            moisture: d.moisture,
+            temperature: d.temperature.map(|t| self.temperature(t)),
+            humidity: d.humidity,
+            tank_fill: d.tank_fill.map(|f| self.tank_fill(f)),
+            raw: raw_element(d)
+        }
+    }
+}
+
+// Events carry readings the way they're stored, whatever the preferences of
+// their subscribers, and name these units in their `units` field.
+pub fn event_units() -> Value {
+    json!({ "temperature": TemperatureUnit::Celsius, "tank_fill": TankUnit::Percent })
+}
+
+// Raw values are passed on the way the sensors reported them.
+pub fn raw_element(d: &DataRow) -> RawElement {
+    RawElement {
+        moisture: d.moisture_raw,
+        temperature: d.temperature_raw,
+        humidity: d.humidity_raw,
+        tank_fill: d.tank_fill_raw
+    }
+}
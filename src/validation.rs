@@ -0,0 +1,357 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::Read;
+use std::ops::Deref;
+
+use chrono::{NaiveTime, Weekday};
+use chrono_tz::Tz;
+use rocket::Outcome;
+use rocket::data::{self, Data, FromDataSimple};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::model::*;
+use crate::calibration;
+use crate::email;
+use crate::plants;
+use crate::station_conf::check_schema;
+use crate::webhooks;
+
+const DEFAULT_LIMIT: u64 = 1 << 20;
+
+const LOGIN_MAX: usize = 32;
+const NAME_MAX: usize = 64;
+const PASS_MAX: usize = 256;
+const EMAIL_MAX: usize = 254;
+const URL_MAX: usize = 2048;
+const SECRET_MAX: usize = 256;
+const LOCATION_MAX: usize = 128;
+const NOTES_MAX: usize = 4096;
+const GARDEN_STATIONS_MAX: usize = 256;
+const SCHEDULE_TIMES_MAX: usize = 48;
+// Station ids are stored as signed 32 bit integers.
+const STATION_ID_MAX: usize = i32::MAX as usize;
+
+// Errors about the request as a whole have an empty field.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.to_string()
+        }
+    }
+}
+
+// The errors of the request being handled, picked up by the 422 catcher.
+#[derive(Debug, Default)]
+pub struct FieldErrors(pub Vec<FieldError>);
+
+impl FieldErrors {
+    pub fn check(&mut self, field: &str, ok: bool, message: &str) {
+        if !ok {
+            self.0.push(FieldError::new(field, message));
+        }
+    }
+
+    fn login(&mut self, field: &str, value: &str) {
+        let ok = !value.is_empty() && value.len() <= LOGIN_MAX && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        self.check(field, ok, "must be 1 to 32 letters, digits, '-', '_' or '.'");
+    }
+
+    fn name(&mut self, field: &str, value: &str) {
+        self.check(field, !value.trim().is_empty(), "must not be empty");
+        self.check(field, value.chars().count() <= NAME_MAX, "must be at most 64 characters long");
+    }
+
+    fn pass(&mut self, field: &str, value: &str) {
+        self.check(field, !value.is_empty(), "must not be empty");
+        self.check(field, value.len() <= PASS_MAX, "must be at most 256 bytes long");
+    }
+
+    // An empty address removes it.
+    fn email(&mut self, field: &str, value: Option<&str>) {
+        if let Some(value) = value.filter(|e| !e.is_empty()) {
+            self.check(field, value.len() <= EMAIL_MAX && value.parse::<lettre::Address>().is_ok(), "must be an email address");
+        }
+    }
+
+    fn station_id(&mut self, field: &str, value: usize) {
+        self.check(field, value <= STATION_ID_MAX, "must be at most 2147483647");
+    }
+
+    fn finite(&mut self, field: &str, value: Option<f32>) {
+        self.check(field, value.map(f32::is_finite).unwrap_or(true), "must be a finite number");
+    }
+
+    fn events(&mut self, field: &str, value: Option<&Vec<String>>, known: &[&str]) {
+        for (i, event) in value.into_iter().flatten().enumerate() {
+            self.check(&format!("{}[{}]", field, i), known.contains(&event.as_str()), "unknown event type");
+        }
+    }
+}
+
+pub trait Validate {
+    fn validate(&self, errors: &mut FieldErrors);
+
+    fn errors(&self) -> Vec<FieldError> {
+        let mut errors = FieldErrors::default();
+        self.validate(&mut errors);
+        errors.0
+    }
+}
+
+// Leaves the errors for the 422 catcher.
+pub fn reject(request: &Request, errors: Vec<FieldError>) -> Status {
+    request.local_cache(|| FieldErrors(errors));
+    Status::UnprocessableEntity
+}
+
+// Fails with 422 and leaves the errors for the catcher if `value` is invalid.
+pub fn check<T: Validate>(request: &Request, value: T) -> data::Outcome<T, String> {
+    let errors = value.errors();
+    if errors.is_empty() {
+        Outcome::Success(value)
+    } else {
+        Outcome::Failure((reject(request, errors), "invalid request".to_string()))
+    }
+}
+
+// Reports errors found by checks that can only be done in the handler, e.g.
+// against the station's schema or state, like those of the request body.
+pub struct Unprocessable<'a, 'r>(&'a Request<'r>);
+
+impl<'a, 'r> Unprocessable<'a, 'r> {
+    pub fn field(&self, field: &str, message: &str) -> Status {
+        self.fields(vec![FieldError::new(field, message)])
+    }
+
+    pub fn fields(&self, errors: Vec<FieldError>) -> Status {
+        reject(self.0, errors)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Unprocessable<'a, 'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(Unprocessable(request))
+    }
+}
+
+// A JSON request body that passed validation.
+#[derive(Debug)]
+pub struct Validated<T>(pub T);
+
+impl<T> Deref for Validated<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate> FromDataSimple for Validated<T> {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let limit = request.limits().get("json").unwrap_or(DEFAULT_LIMIT);
+        let mut buf = Vec::new();
+        if let Err(e) = data.open().take(limit).read_to_end(&mut buf) {
+            return Outcome::Failure((Status::BadRequest, e.to_string()));
+        }
+
+        match serde_json::from_slice(&buf) {
+            Ok(value) => check(request, value).map(Validated),
+            Err(e) => Outcome::Failure((reject(request, vec![FieldError::new("", &e.to_string())]), e.to_string()))
+        }
+    }
+}
+
+impl Validate for StationsReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.station_id("id", self.id);
+        if let Some(name) = self.name.as_ref() {
+            errors.name("name", name);
+        }
+    }
+}
+
+impl Validate for StationReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(name) = self.name.as_ref() {
+            errors.name("name", name);
+        }
+        errors.check("conf_json", self.conf.is_none() || self.conf_json.is_none(), "must not be given together with conf");
+        if let Some(schema) = self.schema.as_ref() {
+            errors.check("schema", check_schema(schema).is_ok(), "must be a valid JSON schema");
+        }
+        if let Some(capacity) = self.tank_capacity {
+            errors.check("tank_capacity", capacity.is_finite() && capacity > 0.0, "must be a positive number");
+        }
+        if let Some(plant) = self.plant.as_ref() {
+            errors.check("plant", plant.len() <= plants::ID_MAX, "must be at most 64 bytes long");
+        }
+        if let Some(location) = self.location.as_ref() {
+            errors.check("location", location.chars().count() <= LOCATION_MAX, "must be at most 128 characters long");
+        }
+        errors.check("latitude", self.latitude.map(|l| l.is_some()) == self.longitude.map(|l| l.is_some()), "must be given or cleared together with longitude");
+        if let Some(Some(latitude)) = self.latitude {
+            errors.check("latitude", (-90.0..=90.0).contains(&latitude), "must be between -90 and 90");
+        }
+        if let Some(Some(longitude)) = self.longitude {
+            errors.check("longitude", (-180.0..=180.0).contains(&longitude), "must be between -180 and 180");
+        }
+        if let Some(timezone) = self.timezone.as_ref().filter(|t| !t.is_empty()) {
+            errors.check("timezone", timezone.parse::<Tz>().is_ok(), "must be an IANA time zone");
+        }
+        if let Some(notes) = self.notes.as_ref() {
+            errors.check("notes", notes.chars().count() <= NOTES_MAX, "must be at most 4096 characters long");
+        }
+        if let Some(photo) = self.photo.as_ref().filter(|p| !p.is_empty()) {
+            errors.check("photo", (photo.starts_with("http://") || photo.starts_with("https://")) && photo.len() <= URL_MAX, "must be an http or https URL");
+        }
+    }
+}
+
+impl Validate for DataReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.finite("moisture", self.moisture);
+        errors.finite("temperature", self.temperature);
+        errors.finite("humidity", self.humidity);
+        errors.finite("tank_fill", self.tank_fill);
+    }
+}
+
+impl Validate for StateReq {
+    fn validate(&self, _errors: &mut FieldErrors) {}
+}
+
+impl Validate for UsersReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.login("login", &self.login);
+        errors.name("name", &self.name);
+        errors.pass("pass", &self.pass);
+        errors.email("email", self.email.as_deref());
+    }
+}
+
+impl Validate for UserReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.name("name", &self.name);
+        errors.pass("pass", &self.pass);
+        errors.email("email", self.email.as_deref());
+    }
+}
+
+impl Validate for NotificationsReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.events("events", self.events.as_ref(), email::EVENT_TYPES);
+        errors.check("digest_interval", self.digest_interval != Some(0), "must be positive");
+    }
+}
+
+impl Validate for UserStationsReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.station_id("id", self.id);
+    }
+}
+
+impl Validate for RollbackReq {
+    fn validate(&self, _errors: &mut FieldErrors) {}
+}
+
+impl Validate for ScheduleReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check("times", !self.times.is_empty(), "must not be empty");
+        errors.check("times", self.times.len() <= SCHEDULE_TIMES_MAX, "must have at most 48 entries");
+        for (i, time) in self.times.iter().enumerate() {
+            errors.check(&format!("times[{}]", i), NaiveTime::parse_from_str(time, "%H:%M").is_ok(), "must be a time of day (HH:MM)");
+        }
+        for (i, day) in self.days.iter().flatten().enumerate() {
+            errors.check(&format!("days[{}]", i), day.parse::<Weekday>().is_ok(), "must be a day of the week");
+        }
+        errors.check("duration", self.duration > 0, "must be positive");
+        // An empty timezone follows the station's.
+        if let Some(timezone) = self.timezone.as_ref().filter(|t| !t.is_empty()) {
+            errors.check("timezone", timezone.parse::<Tz>().is_ok(), "must be an IANA time zone");
+        }
+    }
+}
+
+impl Validate for ControlReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.finite("moisture_low", self.moisture_low);
+        errors.finite("moisture_high", self.moisture_high);
+        if let (Some(low), Some(high)) = (self.moisture_low, self.moisture_high) {
+            errors.check("moisture_high", low < high, "must be above moisture_low");
+        }
+        errors.check("max_duration", self.max_duration > 0, "must be positive");
+        errors.finite("tank_min", self.tank_min);
+    }
+}
+
+impl Validate for AlertRuleReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.finite("threshold", self.threshold);
+    }
+}
+
+impl Validate for CalibrationReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check("points", self.points.len() >= 2, "must have at least 2 entries");
+        errors.check("points", self.points.len() <= calibration::POINTS_MAX, "must have at most 32 entries");
+        for (i, (raw, value)) in self.points.iter().enumerate() {
+            errors.check(&format!("points[{}]", i), raw.is_finite() && value.is_finite(), "must be finite numbers");
+            errors.check(&format!("points[{}]", i), !self.points[..i].iter().any(|(r, _)| r == raw), "must have a distinct raw value");
+        }
+    }
+}
+
+impl Validate for GardenReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.name("name", &self.name);
+        errors.check("stations", self.stations.len() <= GARDEN_STATIONS_MAX, "must have at most 256 entries");
+        for (i, station) in self.stations.iter().enumerate() {
+            errors.station_id(&format!("stations[{}]", i), *station);
+        }
+    }
+}
+
+impl Validate for UnitsReq {
+    fn validate(&self, _errors: &mut FieldErrors) {}
+}
+
+impl Validate for WebhookReq {
+    fn validate(&self, errors: &mut FieldErrors) {
+        let scheme = self.url.starts_with("http://") || self.url.starts_with("https://");
+        errors.check("url", scheme && self.url.len() <= URL_MAX, "must be an http or https URL");
+        errors.events("events", self.events.as_ref(), webhooks::EVENT_TYPES);
+        if let Some(secret) = self.secret.as_ref() {
+            errors.check("secret", secret.len() <= SECRET_MAX, "must be at most 256 bytes long");
+        }
+    }
+}
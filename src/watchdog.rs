@@ -0,0 +1,95 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::sync::{Mutex, Arc};
+use std::time::Duration;
+use std::thread;
+
+use mysql::{Pool, PooledConn};
+use rocket::http::Status;
+use serde_json::json;
+
+use crate::model::*;
+use crate::events::*;
+
+const TICK: Duration = Duration::new(60, 0);
+const OFFLINE_GRACE: usize = 900;
+
+// Stations are seen whenever they send a reading, over HTTP or the WebSocket,
+// and periodically while their WebSocket connection is alive. A station that
+// hasn't been seen for `offline_grace` seconds is considered offline.
+pub fn run(db_conn: Arc<Mutex<Pool>>, conf: HashMap<String, String>, events: Arc<Mutex<EventLog>>) {
+    let grace = conf.get("offline_grace").and_then(|g| g.parse().ok()).unwrap_or(OFFLINE_GRACE);
+
+    loop {
+        thread::sleep(TICK);
+        let time = now();
+        let mut db = match db_conn.lock().unwrap().get_conn() {
+            Ok(db) => db,
+            Err(_) => {
+                println!("[WATCHDOG]: Failed to connect to the database");
+                continue;
+            }
+        };
+
+        let statuses = match get_station_statuses(&mut db) {
+            Ok(statuses) => statuses,
+            Err(_) => {
+                println!("[WATCHDOG]: Failed to load station status");
+                continue;
+            }
+        };
+
+        for status in statuses.into_iter() {
+            let alive = time < status.last_seen + grace;
+            let res = if status.offline && alive {
+                recovered(&status, &mut db, &events)
+            } else if !status.offline && !alive {
+                outage(&status, &mut db, &events)
+            } else {
+                Ok(())
+            };
+
+            if res.is_err() {
+                println!("[WATCHDOG]: Failed to update station {}", status.station);
+            }
+        }
+    }
+}
+
+fn outage(status: &StationStatusRow, db: &mut PooledConn, events: &Arc<Mutex<EventLog>>) -> Result<(), Status> {
+    if set_station_offline(status.station, true, db)? {
+        add_outage(status.station, status.last_seen, db)?;
+        println!("[WATCHDOG]: Station {} is offline (last seen {})", status.station, status.last_seen);
+        events.lock().or(Err(Status::InternalServerError))?.push(status.station, EventKind::Outage, json!({ "last_seen": status.last_seen }).to_string());
+    }
+    Ok(())
+}
+
+fn recovered(status: &StationStatusRow, db: &mut PooledConn, events: &Arc<Mutex<EventLog>>) -> Result<(), Status> {
+    if set_station_offline(status.station, false, db)? {
+        let started = end_outage(status.station, status.last_seen, db)?;
+        println!("[WATCHDOG]: Station {} is back online", status.station);
+        events.lock().or(Err(Status::InternalServerError))?.push(status.station, EventKind::Recovered, json!({
+            "last_seen": status.last_seen,
+            "offline_since": started
+        }).to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,289 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Outgoing webhooks.
+//!
+//! Every event of a user's stations is POSTed as JSON to the user's webhooks
+//! that subscribed to its type:
+//! `{"id": <event id>, "type": "<type>", "station": <id>, "time": <unix>, "data": {...}}`.
+//!
+//! Requests carry the headers `X-Stomata-Event` (the type),
+//! `X-Stomata-Delivery` (the event id, stable across retries) and
+//! `X-Stomata-Signature`, which is `sha256=` followed by the hex encoded
+//! HMAC-SHA256 of the body keyed with the webhook's secret. Any response other
+//! than 2xx is retried with exponential backoff.
+//!
+//! Readings in `data`, `alert` and `fault` events are always in °C and
+//! percent, as stated by their `units` field, regardless of the owner's unit
+//! preferences.
+//!
+//! Every webhook is served by its own worker thread, so a slow endpoint only
+//! holds up its own deliveries. Pending retries are kept in
+//! `webhook_deliveries` and survive restarts.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, Arc};
+use std::sync::mpsc::{self, Sender, Receiver, SendError};
+use std::time::{Duration, Instant};
+use std::thread;
+
+use mysql::{Pool, PooledConn};
+use ring::hmac;
+use serde_json::{json, Value};
+
+use crate::model::*;
+use crate::events::*;
+
+pub const EVENT_TYPES: &[&str] = &["data", "state", "conf", "online", "offline", "alert", "outage", "recovered", "fault"];
+pub const PING_EVENT: &str = "ping";
+
+const TICK: Duration = Duration::from_millis(1000);
+const TIMEOUT: Duration = Duration::new(10, 0);
+const MAX_ATTEMPTS: u32 = 5;
+const BACKOFF: usize = 10;
+const IDLE_TIMEOUT: Duration = Duration::new(300, 0);
+
+struct Delivery {
+    webhook: WebhookRow,
+    event: u64,
+    kind: String,
+    body: String,
+    attempt: u32
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(TIMEOUT).build()
+}
+
+pub fn payload(event: u64, kind: &str, station: usize, data: &str) -> String {
+    json!({
+        "id": event,
+        "type": kind,
+        "station": station,
+        "time": now(),
+        "data": serde_json::from_str::<Value>(data).unwrap_or(Value::Null)
+    }).to_string()
+}
+
+pub fn sign(secret: &str, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, body.as_bytes());
+    format!("sha256={}", tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+// Sends a single request and returns the response status and/or an error
+// description. Only 2xx responses come back without an error.
+fn send(agent: &ureq::Agent, webhook: &WebhookRow, event: u64, kind: &str, body: &str) -> (Option<u16>, Option<String>) {
+    let res = agent.post(&webhook.url)
+        .set("Content-Type", "application/json")
+        .set("X-Stomata-Event", kind)
+        .set("X-Stomata-Delivery", &event.to_string())
+        .set("X-Stomata-Signature", &sign(&webhook.secret, body))
+        .send_string(body);
+
+    match res {
+        Ok(resp) if resp.status() / 100 == 2 => (Some(resp.status()), None),
+        Ok(resp) => (Some(resp.status()), Some(resp.status_text().to_string())),
+        Err(ureq::Error::Status(status, resp)) => (Some(status), Some(resp.status_text().to_string())),
+        Err(e) => (None, Some(e.to_string()))
+    }
+}
+
+pub fn run(db_conn: Arc<Mutex<Pool>>, events: Arc<Mutex<EventLog>>) {
+    let mut last_id = events.lock().unwrap().last_id();
+    let mut workers: HashMap<u64, (Sender<Delivery>, Instant)> = HashMap::new();
+
+    loop {
+        thread::sleep(TICK);
+        // Dropping the sender lets an idle worker finish once its queue is empty.
+        workers.retain(|_, (_, used)| used.elapsed() < IDLE_TIMEOUT);
+
+        let mut db = match db_conn.lock().unwrap().get_conn() {
+            Ok(db) => db,
+            Err(_) => {
+                println!("[HOOKS]: Failed to connect to the database");
+                continue;
+            }
+        };
+
+        let new = events.lock().unwrap().all_since(last_id);
+        if let Some(first) = new.first() {
+            if first.id > last_id + 1 {
+                println!("[HOOKS]: Events {} to {} were dropped from the log before they could be delivered", last_id + 1, first.id - 1);
+            }
+        }
+        if let Some(last) = new.last() {
+            last_id = last.id;
+        }
+
+        let mut due = Vec::new();
+        for event in new.iter() {
+            queue(event, &mut due, &mut db);
+        }
+        retries(&mut due, &mut db);
+
+        for d in due {
+            dispatch(d, &mut workers, &db_conn);
+        }
+    }
+}
+
+fn queue(event: &Event, due: &mut Vec<Delivery>, db: &mut PooledConn) {
+    let owner = match get_station(event.station, db) {
+        Ok(StationRow { owner: Some(owner), .. }) => owner,
+        _ => return
+    };
+    let webhooks = match get_webhooks(&owner, db) {
+        Ok(webhooks) => webhooks,
+        Err(_) => return
+    };
+
+    let kind = event.kind.name();
+    let body = payload(event.id, kind, event.station, &event.data);
+    for webhook in webhooks.into_iter().filter(|w| w.enabled && (w.events.is_empty() || w.events.iter().any(|e| e == kind))) {
+        due.push(Delivery {
+            webhook,
+            event: event.id,
+            kind: kind.to_string(),
+            body: body.clone(),
+            attempt: 0
+        });
+    }
+}
+
+// Claims the stored deliveries that are due, which includes queued pings.
+fn retries(due: &mut Vec<Delivery>, db: &mut PooledConn) {
+    let rows = match get_due_webhook_deliveries(now(), db) {
+        Ok(rows) => rows,
+        Err(_) => return
+    };
+
+    for row in rows {
+        if take_webhook_delivery(&row, db) != Ok(true) {
+            continue;
+        }
+        let webhook = match get_webhook(row.webhook, db) {
+            Ok(webhook) if webhook.enabled || row.kind == PING_EVENT => webhook,
+            _ => continue
+        };
+        if let Some(body) = row.body {
+            due.push(Delivery {
+                webhook,
+                event: row.event,
+                kind: row.kind,
+                body,
+                attempt: row.attempt
+            });
+        }
+    }
+}
+
+fn dispatch(d: Delivery, workers: &mut HashMap<u64, (Sender<Delivery>, Instant)>, db_conn: &Arc<Mutex<Pool>>) {
+    let id = d.webhook.id;
+    let d = match workers.get_mut(&id) {
+        Some((worker, used)) => match worker.send(d) {
+            Ok(_) => {
+                *used = Instant::now();
+                return;
+            },
+            Err(SendError(d)) => d
+        },
+        None => d
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let db_conn = db_conn.clone();
+    thread::spawn(move || work(rx, db_conn));
+    tx.send(d).ok();
+    workers.insert(id, (tx, Instant::now()));
+}
+
+fn work(deliveries: Receiver<Delivery>, db_conn: Arc<Mutex<Pool>>) {
+    let agent = agent();
+    for d in deliveries.iter() {
+        deliver(&agent, d, &db_conn);
+    }
+}
+
+// When to retry a delivery that failed on its `attempt`th try, the delay
+// doubles with every attempt.
+fn retry_at(attempt: u32, now: usize) -> Option<usize> {
+    if attempt >= MAX_ATTEMPTS {
+        None
+    } else {
+        Some(now + (BACKOFF << (attempt - 1)))
+    }
+}
+
+// Records the attempt and stores the delivery for a retry if it failed. Pings
+// aren't retried.
+fn deliver(agent: &ureq::Agent, mut d: Delivery, db_conn: &Arc<Mutex<Pool>>) {
+    d.attempt += 1;
+    let (status, error) = send(agent, &d.webhook, d.event, &d.kind, &d.body);
+
+    let next_try = if error.is_none() || d.kind == PING_EVENT {
+        None
+    } else {
+        retry_at(d.attempt, now()).or_else(|| {
+            println!("[HOOKS]: Giving up on event {} for webhook {}", d.event, d.webhook.id);
+            None
+        })
+    };
+
+    let recorded = db_conn.lock().unwrap().get_conn().or(Err(())).and_then(|mut db| {
+        let id = add_webhook_delivery(d.webhook.id, d.event, &d.kind, d.attempt, status, error.as_deref(), &mut db).or(Err(()))?;
+        match next_try {
+            Some(next_try) => retry_webhook_delivery(id, &d.body, next_try, &mut db).or(Err(())),
+            None => Ok(())
+        }
+    });
+    if recorded.is_err() {
+        println!("[HOOKS]: Failed to record the delivery of event {} to webhook {}", d.event, d.webhook.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_the_body_with_hmac_sha256() {
+        assert_eq!(sign("Jefe", "what do ya want for nothing?"), "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[test]
+    fn wraps_the_event_data() {
+        let body: Value = serde_json::from_str(&payload(7, "data", 3, r#"{"moisture":42}"#)).unwrap();
+        assert_eq!(body["id"], 7);
+        assert_eq!(body["type"], "data");
+        assert_eq!(body["station"], 3);
+        assert!(body["time"].is_u64());
+        assert_eq!(body["data"]["moisture"], 42);
+
+        let body: Value = serde_json::from_str(&payload(7, "data", 3, "not json")).unwrap();
+        assert!(body["data"].is_null());
+    }
+
+    #[test]
+    fn backs_off_exponentially_until_giving_up() {
+        assert_eq!(retry_at(1, 1000), Some(1000 + BACKOFF));
+        assert_eq!(retry_at(2, 1000), Some(1000 + 2 * BACKOFF));
+        assert_eq!(retry_at(MAX_ATTEMPTS - 1, 1000), Some(1000 + (BACKOFF << (MAX_ATTEMPTS - 2))));
+        assert_eq!(retry_at(MAX_ATTEMPTS, 1000), None);
+    }
+}
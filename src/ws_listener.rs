@@ -0,0 +1,249 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant, SystemTime};
+use std::thread;
+
+use rustls::{NoClientAuth, ServerConfig, ServerSession, Session, StreamOwned};
+use rustls::internal::pemfile;
+use websocket::sync::Client;
+use websocket::sync::server::IntoWs;
+use websocket::sync::stream::{AsTcpStream, NetworkStream};
+
+const WS_HOST: &str = "0.0.0.0";
+const WS_PORT: u16 = 8001;
+const HANDSHAKE_TIMEOUT: Duration = Duration::new(5, 0);
+const RELOAD_INTERVAL: Duration = Duration::new(60, 0);
+const WRITE_TIMEOUT: Duration = Duration::new(10, 0);
+const WRITE_RETRY: Duration = Duration::from_millis(10);
+
+pub type WsStream = Box<dyn NetworkStream + Send>;
+pub type WsClient = Client<WsStream>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListenMode {
+    Standalone,
+    Http,
+    Both
+}
+
+impl ListenMode {
+    pub fn from_conf(conf: &HashMap<String, String>) -> Self {
+        match conf.get("ws_listen").map(String::as_str) {
+            Some("http") => ListenMode::Http,
+            Some("both") => ListenMode::Both,
+            _ => ListenMode::Standalone
+        }
+    }
+
+    pub fn standalone(&self) -> bool {
+        *self != ListenMode::Http
+    }
+
+    pub fn http(&self) -> bool {
+        *self != ListenMode::Standalone
+    }
+}
+
+// Accepts stations on the standalone port. Handshakes are done on their own
+// thread so a slow client doesn't hold up the notifier, which receives the
+// finished clients through `upgrades` like those upgraded by `http_mux`.
+pub fn run(conf: HashMap<String, String>, upgrades: Sender<WsClient>) {
+    let host = conf.get("ws_host").map(String::as_str).unwrap_or(WS_HOST);
+    let port = conf.get("ws_port").and_then(|p| p.parse().ok()).unwrap_or(WS_PORT);
+    let mut tls = Tls::from_conf(&conf);
+
+    let listener = TcpListener::bind((host, port)).unwrap();
+    println!("[WS]: Listening on {}://{}:{}", if tls.is_some() { "wss" } else { "ws" }, host, port);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue
+        };
+
+        if let Some(tls) = tls.as_mut() {
+            tls.reload();
+        }
+        let stream: WsStream = match &tls {
+            Some(tls) => tls.wrap(stream),
+            None => Box::new(stream)
+        };
+
+        let upgrades = upgrades.clone();
+        thread::spawn(move || {
+            if let Some(cli) = upgrade(stream) {
+                upgrades.send(cli).ok();
+            }
+        });
+    }
+}
+
+pub fn upgrade(stream: WsStream) -> Option<WsClient> {
+    let addr = stream.as_tcp().peer_addr().ok()?;
+    stream.as_tcp().set_nonblocking(false).ok()?;
+    stream.as_tcp().set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok()?;
+
+    match stream.into_ws() {
+        Ok(upgrade) => match upgrade.accept() {
+            Ok(cli) => {
+                cli.stream_ref().as_tcp().set_read_timeout(None).ok()?;
+                cli.set_nonblocking(true).ok()?;
+                Some(cli)
+            }
+            Err((_, e)) => {
+                println!("[WS]: Handshake with {} failed ({})", addr, e);
+                None
+            }
+        },
+        Err((_, _, _, e)) => {
+            println!("[WS]: Handshake with {} failed ({:?})", addr, e);
+            None
+        }
+    }
+}
+
+pub struct Tls {
+    cert: PathBuf,
+    key: PathBuf,
+    modified: Option<SystemTime>,
+    checked: Instant,
+    config: Arc<ServerConfig>
+}
+
+impl Tls {
+    pub fn from_conf(conf: &HashMap<String, String>) -> Option<Self> {
+        match (conf.get("ws_tls_cert"), conf.get("ws_tls_key")) {
+            (Some(cert), Some(key)) => Some(Self::load(PathBuf::from(cert), PathBuf::from(key)).unwrap()),
+            _ => None
+        }
+    }
+
+    pub fn wrap(&self, stream: TcpStream) -> WsStream {
+        Box::new(TlsStream(StreamOwned::new(ServerSession::new(&self.config), stream)))
+    }
+
+    fn load(cert: PathBuf, key: PathBuf) -> io::Result<Self> {
+        let modified = Self::modified(&cert, &key);
+        let config = Self::config(&cert, &key)?;
+        Ok(Self {
+            cert,
+            key,
+            modified,
+            checked: Instant::now(),
+            config
+        })
+    }
+
+    // Certificates are replaced in place by e.g. cert-manager, so the files
+    // are checked for changes periodically and the old configuration is kept
+    // if the new one can't be loaded.
+    pub fn reload(&mut self) {
+        if self.checked.elapsed() < RELOAD_INTERVAL {
+            return;
+        }
+        self.checked = Instant::now();
+
+        let modified = Self::modified(&self.cert, &self.key);
+        if modified != self.modified {
+            match Self::config(&self.cert, &self.key) {
+                Ok(config) => {
+                    self.config = config;
+                    self.modified = modified;
+                    println!("[WS]: Reloaded TLS certificate");
+                }
+                Err(e) => println!("[WS]: Failed to reload TLS certificate ({})", e)
+            }
+        }
+    }
+
+    fn modified(cert: &PathBuf, key: &PathBuf) -> Option<SystemTime> {
+        let cert = fs::metadata(cert).and_then(|m| m.modified()).ok()?;
+        let key = fs::metadata(key).and_then(|m| m.modified()).ok()?;
+        Some(cert.max(key))
+    }
+
+    fn config(cert: &PathBuf, key: &PathBuf) -> io::Result<Arc<ServerConfig>> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        let certs = pemfile::certs(&mut BufReader::new(File::open(cert)?)).or(Err(invalid("invalid certificate")))?;
+        let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key)?)).or(Err(invalid("invalid key")))?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key)?)).or(Err(invalid("invalid key")))?;
+        }
+        let key = keys.into_iter().next().ok_or(invalid("no key found"))?;
+
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.set_single_cert(certs, key).or(Err(invalid("certificate doesn't match key")))?;
+        Ok(Arc::new(config))
+    }
+}
+
+struct TlsStream(StreamOwned<ServerSession, TcpStream>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+// The notifier polls its connections without blocking, but once rustls has
+// taken data it can't be taken back, so a write that would block is retried
+// until the records are sent or `WRITE_TIMEOUT` has passed.
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.0.sess.is_handshaking() {
+            return self.0.write(buf);
+        }
+        let n = self.0.sess.write(buf)?;
+        self.send()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.sess.flush()?;
+        self.send()
+    }
+}
+
+impl TlsStream {
+    fn send(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        while self.0.sess.wants_write() {
+            match self.0.sess.write_tls(&mut self.0.sock) {
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && started.elapsed() < WRITE_TIMEOUT => thread::sleep(WRITE_RETRY),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsTcpStream for TlsStream {
+    fn as_tcp(&self) -> &TcpStream {
+        &self.0.sock
+    }
+}
@@ -16,173 +16,348 @@
  * along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::collections::HashMap;
 use std::sync::{Mutex, Arc};
+use std::sync::mpsc::Receiver;
 use std::time::{Duration, Instant};
 use std::thread;
 
 use mysql::Pool;
+use rocket::http::Status;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use websocket::sync::{Server, Client, stream::TcpStream};
+use serde_json::Value;
 use websocket::OwnedMessage;
+use websocket::result::WebSocketResult;
 
 use crate::model::*;
 use crate::auth::*;
+use crate::bus::Bus;
+use crate::encoding::Encoding;
+use crate::events::*;
+use crate::ingest::*;
+use crate::state::StationState;
+use crate::station_conf::check_schema;
+use crate::validation::Validate;
+use crate::ws_listener::*;
+use crate::ws_protocol::*;
 
-const WS_PORT: usize = 8001;
-const ALIVE_TIMEOUT: Duration = Duration::new(600, 0);
+const PING_INTERVAL: Duration = Duration::new(60, 0);
+const ALIVE_TIMEOUT: Duration = Duration::new(180, 0);
+const TOUCH_INTERVAL: Duration = Duration::new(60, 0);
+const HANDSHAKE_TIMEOUT: Duration = Duration::new(30, 0);
 
-pub fn run(db_conn: Arc<Mutex<Pool>>, reqs: Arc<Mutex<Vec<WsRequest>>>) {
-    let mut server = Server::bind(format!("0.0.0.0:{}", WS_PORT)).unwrap();
-    server.set_nonblocking(true).unwrap();
+#[derive(Debug, Clone, Copy)]
+struct Keepalive {
+    ping_interval: Duration,
+    alive_timeout: Duration,
+    handshake_timeout: Duration
+}
+
+impl Keepalive {
+    fn from_conf(conf: &HashMap<String, String>) -> Self {
+        let secs = |key: &str, default: Duration| conf.get(key).and_then(|s| s.parse().ok()).map(Duration::from_secs).unwrap_or(default);
+        Self {
+            ping_interval: secs("ws_ping_interval", PING_INTERVAL),
+            alive_timeout: secs("ws_alive_timeout", ALIVE_TIMEOUT),
+            handshake_timeout: secs("ws_handshake_timeout", HANDSHAKE_TIMEOUT)
+        }
+    }
+}
+
+// Clients arrive through `upgrades` with their handshake already done, see
+// `ws_listener::run` and `http_mux::run`.
+pub fn run(db_conn: Arc<Mutex<Pool>>, conf: HashMap<String, String>, upgrades: Receiver<WsClient>, reqs: Arc<Mutex<Box<dyn Bus>>>, events: Arc<Mutex<EventLog>>) {
+    let keepalive = Keepalive::from_conf(&conf);
     println!("[WS]: Started");
 
     let mut connections: Vec<Connection> = Vec::new();
     let mut stations: Vec<(Connection, usize)> = Vec::new();
 
     loop {
-        if let Ok(request) = server.accept() {
-            let cli = request.accept().unwrap();
-            let addr = cli.peer_addr().unwrap();
-            cli.set_nonblocking(true).unwrap();
+        while let Ok(cli) = upgrades.try_recv() {
+            let addr = match cli.peer_addr() {
+                Ok(addr) => addr,
+                Err(_) => continue
+            };
             connections.push(Connection {
                 cli,
-                last_seen: Instant::now()
+                connected: Instant::now(),
+                last_seen: Instant::now(),
+                last_ping: Instant::now(),
+                last_touch: Instant::now(),
+                version: Version::V0,
+                encoding: Encoding::Json,
+                next_id: 1
             });
             println!("[WS]: Connection from {}", addr);
         }
 
-        connections = connections.into_iter().filter_map(|c| process_con(c, &mut stations, db_conn.clone())).collect();
-        stations = stations.into_iter().filter_map(|s| process_station(s)).collect();
+        let before: Vec<usize> = stations.iter().map(|(_, id)| *id).collect();
+        connections = connections.into_iter().filter_map(|c| process_con(c, &mut stations, keepalive, db_conn.clone(), events.clone())).collect();
+        stations = stations.into_iter().filter_map(|s| process_station(s, keepalive, &conf, db_conn.clone(), reqs.clone(), events.clone())).collect();
+
+        let mut bus = reqs.lock().unwrap();
+        stations.iter().filter(|(_, id)| !before.contains(id)).for_each(|(_, id)| bus.connected(*id).unwrap_or(()));
+        before.iter().filter(|id| !stations.iter().any(|(_, s)| s == *id)).for_each(|id| bus.disconnected(*id).unwrap_or(()));
 
-        let reqs = std::mem::replace(reqs.lock().unwrap().as_mut(), Vec::new());
+        let reqs = bus.poll().unwrap_or_default();
+        drop(bus);
+        let mut failed = Vec::new();
         for r in reqs.into_iter() {
-            match r {
-                WsRequest::UpdateState(r) => {
-                    if let Some(station) = stations.iter_mut().find(|(_, id)| id == &r.id) {
-                        let msg = OwnedMessage::Text(serde_json::to_string(&StateMessage {
-                            state: r.state
-                        }).unwrap());
-                        station.0.cli.send_message(&msg).unwrap();
-                    }
+            let id = r.station();
+            let reply = match r {
+                WsRequest::UpdateState(r) => Reply::State {
+                    state: r.state
                 },
-                WsRequest::UpdateConf(r) => {
-                    if let Some(station) = stations.iter_mut().find(|(_, id)| id == &r.id) {
-                        let msg = OwnedMessage::Text(serde_json::to_string(&ConfMessage {
-                            conf: r.conf
-                        }).unwrap());
-                        station.0.cli.send_message(&msg).unwrap();
-                    }
+                WsRequest::UpdateConf(r) => Reply::Conf {
+                    conf: r.conf,
+                    revision: r.revision
+                }
+            };
+            if let Some(station) = stations.iter_mut().find(|(_, s)| s == &id) {
+                if station.0.send(None, &reply).is_err() {
+                    failed.push(id);
                 }
             }
         }
+        stations.retain(|(_, id)| !failed.contains(id));
+        failed.into_iter().for_each(|id| disconnected(id, "error", &events));
 
         thread::sleep(Duration::from_millis(1000));
     }
 }
 
-fn process_con(mut con: Connection, stations: &mut Vec<(Connection, usize)>, db_conn: Arc<Mutex<Pool>>) -> Option<Connection> {
+fn process_con(mut con: Connection, stations: &mut Vec<(Connection, usize)>, keepalive: Keepalive, db_conn: Arc<Mutex<Pool>>, events: Arc<Mutex<EventLog>>) -> Option<Connection> {
     if let Ok(msg) = con.cli.recv_message() {
-        match msg {
+        let registered = match msg {
             OwnedMessage::Close(_) => {
                 let msg = OwnedMessage::Close(None);
-                con.cli.send_message(&msg).unwrap();
+                con.cli.send_message(&msg).unwrap_or(());
                 return None;
             }
             OwnedMessage::Ping(ping) => {
                 let msg = OwnedMessage::Pong(ping);
-                con.cli.send_message(&msg).unwrap();
-                con.last_seen = Instant::now();
+                con.cli.send_message(&msg).map(|_| None)
             }
-            OwnedMessage::Text(data) => {
-                if let Ok(reg) = serde_json::from_str::<RegisterMessage>(&data) {
-                    let mut db = db_conn.lock().unwrap().get_conn().unwrap();
-                    if let Ok(token) = get_station(reg.id, &mut db).map(|s| s.token) {
-                        let auth = BasicAuth::from_parts(&reg.id.to_string(), &reg.token);
-                        if auth.verify(&token) {
-                            con.last_seen = Instant::now();
-                            let msg = OwnedMessage::Text("{}".to_string());
-                            con.cli.send_message(&msg).unwrap();
-
-                            stations.iter_mut().filter(|(_, id)| id == &reg.id).for_each(|(con, _)| con.cli.send_message(&OwnedMessage::Close(None)).unwrap());
-                            stations.retain(|(_, id)| id != &reg.id);
-                            stations.push((con, reg.id));
-
-                            println!("[WS]: Station {:?} registered", reg.id);
-                            return None;
-                        }
-                    }
-                }
+            OwnedMessage::Text(data) => register(&mut con, data.as_bytes(), false, &db_conn),
+            OwnedMessage::Binary(data) => register(&mut con, &data, true, &db_conn),
+            _ => Ok(None)
+        };
+
+        match registered {
+            Ok(Some(station)) => {
+                stations.iter_mut().filter(|(_, s)| s == &station).for_each(|(con, _)| con.cli.send_message(&OwnedMessage::Close(None)).unwrap_or(()));
+                stations.retain(|(_, s)| s != &station);
+                println!("[WS]: Station {:?} registered (v{}, {:?})", station, con.version.number(), con.encoding);
+                stations.push((con, station));
+                events.lock().unwrap().push(station, EventKind::Online, "{}".to_string());
+                return None;
             }
-            _ => (),
+            Ok(None) => (),
+            Err(_) => return None
         }
     }
 
-    if con.last_seen.elapsed() < ALIVE_TIMEOUT {
+    if con.connected.elapsed() < keepalive.handshake_timeout {
         Some(con)
     } else {
         None
     }
 }
 
-fn process_station((mut con, id): (Connection, usize)) -> Option<(Connection, usize)> {
+fn process_station((mut con, id): (Connection, usize), keepalive: Keepalive, conf: &HashMap<String, String>, db_conn: Arc<Mutex<Pool>>, reqs: Arc<Mutex<Box<dyn Bus>>>, events: Arc<Mutex<EventLog>>) -> Option<(Connection, usize)> {
     if let Ok(msg) = con.cli.recv_message() {
-        match msg {
+        con.last_seen = Instant::now();
+        let sent = match msg {
             OwnedMessage::Close(_) => {
                 let msg = OwnedMessage::Close(None);
-                con.cli.send_message(&msg).unwrap();
-                println!("[WS]: Station {:?} disconnected (close)", id);
+                con.cli.send_message(&msg).unwrap_or(());
+                disconnected(id, "close", &events);
                 return None;
             }
             OwnedMessage::Ping(ping) => {
                 let msg = OwnedMessage::Pong(ping);
-                con.cli.send_message(&msg).unwrap();
-                con.last_seen = Instant::now();
+                con.cli.send_message(&msg)
             }
-            _ => (),
+            OwnedMessage::Text(data) => receive(&mut con, id, data.as_bytes(), false, conf, &db_conn, &reqs, &events),
+            OwnedMessage::Binary(data) => receive(&mut con, id, &data, true, conf, &db_conn, &reqs, &events),
+            _ => Ok(()),
+        };
+
+        if sent.is_err() {
+            disconnected(id, "error", &events);
+            return None;
         }
     }
 
-    if con.last_seen.elapsed() < ALIVE_TIMEOUT {
+    // Lets the watchdog know the station is alive even if it sends no readings.
+    if con.last_touch.elapsed() >= TOUCH_INTERVAL && con.last_seen > con.last_touch {
+        con.last_touch = Instant::now();
+        let db = db_conn.lock().unwrap().get_conn();
+        if let Ok(mut db) = db {
+            touch_station(id, now(), &mut db).unwrap_or(());
+        }
+    }
+
+    if con.last_ping.elapsed() >= keepalive.ping_interval {
+        con.last_ping = Instant::now();
+        if con.cli.send_message(&OwnedMessage::Ping(Vec::new())).is_err() {
+            disconnected(id, "error", &events);
+            return None;
+        }
+    }
+
+    if con.last_seen.elapsed() < keepalive.alive_timeout {
         Some((con, id))
     } else {
-        println!("[WS]: Station {:?} disconnected (timeout)", id);
+        disconnected(id, "timeout", &events);
         None
     }
 }
 
-struct Connection {
-    cli: Client<TcpStream>,
-    last_seen: Instant
+fn disconnected(id: usize, reason: &str, events: &Arc<Mutex<EventLog>>) {
+    println!("[WS]: Station {:?} disconnected ({})", id, reason);
+    events.lock().unwrap().push(id, EventKind::Offline, "{}".to_string());
 }
 
-#[derive(Debug, Deserialize)]
-struct RegisterMessage {
-    id: usize,
-    token: String
+// Errors are only returned if a reply couldn't be written, in which case the
+// connection is dropped.
+fn register(con: &mut Connection, data: &[u8], binary: bool, db_conn: &Arc<Mutex<Pool>>) -> WebSocketResult<Option<usize>> {
+    con.encoding = Encoding::detect(data, binary);
+    match decode(data, con.encoding) {
+        Ok(Incoming { version, id, req: Request::Register { station, token, schema } }) => {
+            con.version = version;
+            let mut db = match db_conn.lock().unwrap().get_conn() {
+                Ok(db) => db,
+                Err(_) => {
+                    con.send_error(id, ErrorCode::Internal)?;
+                    return Ok(None);
+                }
+            };
+            match get_station(station, &mut db) {
+                Ok(mut row) if BasicAuth::from_parts(&station.to_string(), &token).verify(&row.token) => {
+                    if let Some(schema) = schema {
+                        if check_schema(&schema).is_err() {
+                            con.send_error(id, ErrorCode::InvalidSchema)?;
+                            return Ok(None);
+                        }
+                        row.schema = Some(schema);
+                        if update_station(row, &mut db).is_err() {
+                            con.send_error(id, ErrorCode::Internal)?;
+                            return Ok(None);
+                        }
+                    }
+
+                    con.last_seen = Instant::now();
+                    con.last_touch = Instant::now();
+                    touch_station(station, now(), &mut db).unwrap_or(());
+                    con.send(id, &Reply::Registered)?;
+                    return Ok(Some(station));
+                }
+                Ok(_) => con.send_error(id, ErrorCode::Unauthorized)?,
+                Err(_) => con.send_error(id, ErrorCode::UnknownStation)?
+            }
+        }
+        Ok(Incoming { version, id, .. }) => {
+            con.version = version;
+            con.send_error(id, ErrorCode::NotRegistered)?;
+        }
+        Err(e) => {
+            con.version = e.version;
+            con.send_error(e.id, e.code)?;
+        }
+    }
+    Ok(None)
 }
 
-#[derive(Debug, Serialize)]
-struct StateMessage {
-    state: String
+fn receive(con: &mut Connection, id: usize, data: &[u8], binary: bool, conf: &HashMap<String, String>, db_conn: &Arc<Mutex<Pool>>, reqs: &Arc<Mutex<Box<dyn Bus>>>, events: &Arc<Mutex<EventLog>>) -> WebSocketResult<()> {
+    if binary != con.encoding.is_binary() {
+        return con.send_error(None, ErrorCode::Malformed);
+    }
+
+    match decode(data, con.encoding) {
+        Ok(Incoming { id: msg_id, req: Request::Data(data), .. }) if !data.errors().is_empty() => con.send_error(msg_id, ErrorCode::InvalidData),
+        Ok(Incoming { id: msg_id, req: Request::Data(data), .. }) => {
+            let mut db = match db_conn.lock().unwrap().get_conn() {
+                Ok(db) => db,
+                Err(_) => return con.send_error(msg_id, ErrorCode::Internal)
+            };
+            match ingest(id, &data, conf, &mut db, reqs, events) {
+                Ok(_) => con.send(msg_id, &Reply::Ack),
+                Err(_) => con.send_error(msg_id, ErrorCode::Internal)
+            }
+        }
+        Ok(Incoming { id: msg_id, req: Request::ConfAck { revision }, .. }) => {
+            let mut db = match db_conn.lock().unwrap().get_conn() {
+                Ok(db) => db,
+                Err(_) => return con.send_error(msg_id, ErrorCode::Internal)
+            };
+            match set_conf_acked(id, revision, &mut db) {
+                Ok(_) => con.send(msg_id, &Reply::Ack),
+                Err(Status::NotFound) | Err(Status::Conflict) => con.send_error(msg_id, ErrorCode::InvalidRevision),
+                Err(_) => con.send_error(msg_id, ErrorCode::Internal)
+            }
+        }
+        Ok(Incoming { id: msg_id, req: Request::Register { .. }, .. }) => con.send_error(msg_id, ErrorCode::Malformed),
+        Err(e) => con.send_error(e.id, e.code)
+    }
+}
+
+struct Connection {
+    cli: WsClient,
+    connected: Instant,
+    last_seen: Instant,
+    last_ping: Instant,
+    last_touch: Instant,
+    version: Version,
+    encoding: Encoding,
+    next_id: u64
 }
 
-#[derive(Debug, Serialize)]
-struct ConfMessage {
-    conf: String
+impl Connection {
+    fn send(&mut self, reply_to: Option<u64>, reply: &Reply) -> WebSocketResult<()> {
+        let data = encode(self.version, self.encoding, self.next_id, reply_to, reply);
+        let msg = if self.encoding.is_binary() {
+            OwnedMessage::Binary(data)
+        } else {
+            OwnedMessage::Text(String::from_utf8(data).unwrap())
+        };
+        self.next_id += 1;
+        self.cli.send_message(&msg)
+    }
+
+    fn send_error(&mut self, reply_to: Option<u64>, code: ErrorCode) -> WebSocketResult<()> {
+        self.send(reply_to, &Reply::Error {
+            code,
+            message: code.message().to_string()
+        })
+    }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
 pub enum WsRequest {
     UpdateState(WsUpdateState),
     UpdateConf(WsUpdateConf)
 }
 
+impl WsRequest {
+    pub fn station(&self) -> usize {
+        match self {
+            WsRequest::UpdateState(r) => r.id,
+            WsRequest::UpdateConf(r) => r.id
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct WsUpdateState {
     pub id: usize,
-    pub state: String
+    pub state: StationState
 }
 
+#[derive(Debug, Serialize, Deserialize)]
 pub struct WsUpdateConf {
     pub id: usize,
-    pub conf: String
+    pub conf: Value,
+    pub revision: u64
 }
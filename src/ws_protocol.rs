@@ -0,0 +1,254 @@
+/*
+ * stomata - Backend for the Thyme project
+ * Copyright (C) 2021 TechnoElf
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Station WebSocket protocol.
+//!
+//! Version 1 wraps every message in an envelope of the form
+//! `{"type": "<type>", "version": 1, "id": <n>, ...}`. `id` is chosen by the
+//! sender and increases per message; replies carry the id of the message they
+//! answer in `reply_to`.
+//!
+//! Station to server:
+//! - `register` with `station`, `token` and optionally `schema`, a JSON Schema
+//!   that configuration updates for the station are validated against.
+//!   `version` is the highest protocol version the station speaks; the server
+//!   answers in the negotiated version.
+//! - `data` with the optional fields `moisture`, `temperature`, `humidity` and
+//!   `tank_fill`. Answered with `ack` once stored or `error` otherwise.
+//! - `conf_ack` with the `revision` of a `conf` message once the station has
+//!   applied it. Answered with `ack`, or `error` if the revision is unknown or
+//!   older than one acknowledged before.
+//!
+//! Server to station:
+//! - `registered` in reply to a successful `register`.
+//! - `ack` in reply to a stored `data` message.
+//! - `state` with `state`, and `conf` with `conf` and `revision`, whenever a
+//!   user changes them.
+//! - `error` with `code` and `message`, optionally in reply to a message.
+//!
+//! Version 0 is the legacy format without an envelope: `{"id", "token"}` (and
+//! optionally `"schema"`) to register, answered by `{}`, readings as `{"seq", ...}` answered by
+//! `{"ack"}` or `{"nack"}`, and bare `{"state"}` and `{"conf"}` objects, where
+//! `conf` is the configuration encoded as a JSON string.
+//! Errors are sent as `{"error": "<code>"}`.
+//!
+//! Messages are JSON in text frames by default. A station may instead send its
+//! registration as CBOR or MessagePack in a binary frame, in which case every
+//! following message in either direction uses that encoding.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::encoding::Encoding;
+use crate::model::DataReq;
+use crate::state::StationState;
+use crate::station_conf::conf_string;
+
+pub const CURRENT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    V0,
+    V1
+}
+
+impl Version {
+    pub fn number(&self) -> u32 {
+        match self {
+            Version::V0 => 0,
+            Version::V1 => 1
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Request {
+    Register { station: usize, token: String, schema: Option<Value> },
+    Data(DataReq),
+    ConfAck { revision: u64 }
+}
+
+#[derive(Debug)]
+pub struct Incoming {
+    pub version: Version,
+    pub id: Option<u64>,
+    pub req: Request
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Malformed,
+    UnsupportedVersion,
+    UnknownStation,
+    Unauthorized,
+    NotRegistered,
+    InvalidSchema,
+    InvalidData,
+    InvalidRevision,
+    Internal
+}
+
+impl ErrorCode {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorCode::Malformed => "malformed message",
+            ErrorCode::UnsupportedVersion => "unsupported protocol version",
+            ErrorCode::UnknownStation => "unknown station",
+            ErrorCode::Unauthorized => "invalid station token",
+            ErrorCode::NotRegistered => "connection is not registered",
+            ErrorCode::InvalidSchema => "invalid configuration schema",
+            ErrorCode::InvalidData => "invalid sensor data",
+            ErrorCode::InvalidRevision => "unknown or outdated configuration revision",
+            ErrorCode::Internal => "internal server error"
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub version: Version,
+    pub id: Option<u64>,
+    pub code: ErrorCode
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
+    Registered,
+    Ack,
+    State { state: StationState },
+    Conf { conf: Value, revision: u64 },
+    Error { code: ErrorCode, message: String }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StationMessage {
+    Register { station: usize, token: String, schema: Option<Value> },
+    Data(DataReq),
+    ConfAck { revision: u64 }
+}
+
+#[derive(Debug, Deserialize)]
+struct StationEnvelope {
+    version: u32,
+    id: u64,
+    #[serde(flatten)]
+    msg: StationMessage
+}
+
+#[derive(Debug, Serialize)]
+struct ServerEnvelope<'a> {
+    version: u32,
+    id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<u64>,
+    #[serde(flatten)]
+    msg: &'a Reply
+}
+
+#[derive(Debug, Deserialize)]
+struct LegacyRegisterMessage {
+    id: usize,
+    token: String,
+    schema: Option<Value>
+}
+
+#[derive(Debug, Deserialize)]
+struct LegacyDataMessage {
+    seq: u64,
+    #[serde(flatten)]
+    data: DataReq
+}
+
+#[derive(Debug, Serialize)]
+struct LegacyRegisteredMessage {}
+
+#[derive(Debug, Serialize)]
+struct LegacyAckMessage {
+    ack: u64
+}
+
+#[derive(Debug, Serialize)]
+struct LegacyNackMessage {
+    nack: u64
+}
+
+#[derive(Debug, Serialize)]
+struct LegacyStateMessage<'a> {
+    state: &'a str
+}
+
+#[derive(Debug, Serialize)]
+struct LegacyConfMessage {
+    conf: String
+}
+
+#[derive(Debug, Serialize)]
+struct LegacyErrorMessage {
+    error: ErrorCode
+}
+
+pub fn decode(data: &[u8], encoding: Encoding) -> Result<Incoming, ProtocolError> {
+    let value: Value = encoding.from_slice(data).or(Err(ProtocolError { version: Version::V0, id: None, code: ErrorCode::Malformed }))?;
+
+    if value.get("type").is_some() {
+        let id = value.get("id").and_then(Value::as_u64);
+        let version = match value.get("version").and_then(Value::as_u64).unwrap_or(0).min(CURRENT_VERSION as u64) {
+            1 => Version::V1,
+            _ => return Err(ProtocolError { version: Version::V1, id, code: ErrorCode::UnsupportedVersion })
+        };
+
+        let env: StationEnvelope = serde_json::from_value(value).or(Err(ProtocolError { version: Version::V1, id, code: ErrorCode::Malformed }))?;
+        let req = match env.msg {
+            StationMessage::Register { station, token, schema } => Request::Register { station, token, schema },
+            StationMessage::Data(data) => Request::Data(data),
+            StationMessage::ConfAck { revision } => Request::ConfAck { revision }
+        };
+        Ok(Incoming { version, id: Some(env.id), req })
+    } else if let Ok(reg) = serde_json::from_value::<LegacyRegisterMessage>(value.clone()) {
+        Ok(Incoming { version: Version::V0, id: None, req: Request::Register { station: reg.id, token: reg.token, schema: reg.schema } })
+    } else if let Ok(data) = serde_json::from_value::<LegacyDataMessage>(value) {
+        Ok(Incoming { version: Version::V0, id: Some(data.seq), req: Request::Data(data.data) })
+    } else {
+        Err(ProtocolError { version: Version::V0, id: None, code: ErrorCode::Malformed })
+    }
+}
+
+pub fn encode(version: Version, encoding: Encoding, id: u64, reply_to: Option<u64>, reply: &Reply) -> Vec<u8> {
+    let msg = match version {
+        Version::V1 => encoding.to_vec(&ServerEnvelope {
+            version: version.number(),
+            id,
+            reply_to,
+            msg: reply
+        }),
+        Version::V0 => match reply {
+            Reply::Registered => encoding.to_vec(&LegacyRegisteredMessage {}),
+            Reply::Ack => encoding.to_vec(&LegacyAckMessage { ack: reply_to.unwrap_or(0) }),
+            Reply::State { state } => encoding.to_vec(&LegacyStateMessage { state: state.as_str() }),
+            Reply::Conf { conf, .. } => encoding.to_vec(&LegacyConfMessage { conf: conf_string(conf) }),
+            Reply::Error { code, .. } => match (code, reply_to) {
+                (ErrorCode::Internal, Some(seq)) => encoding.to_vec(&LegacyNackMessage { nack: seq }),
+                _ => encoding.to_vec(&LegacyErrorMessage { error: *code })
+            }
+        }
+    };
+    msg.unwrap()
+}